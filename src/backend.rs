@@ -0,0 +1,626 @@
+use chrono::{DateTime, FixedOffset};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::ffi::CStr;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::time::{Duration, SystemTime};
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+mod archive;
+mod borg;
+mod kopia;
+mod mirror;
+mod restic;
+
+pub use archive::Archive;
+pub use borg::Borg;
+pub use kopia::Kopia;
+pub use mirror::Mirror;
+pub use restic::{Repository, Restic};
+
+/// Metadata of a single snapshot in a backup repository.
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub id: String,
+    pub time: DateTime<FixedOffset>,
+    pub paths: Vec<PathBuf>,
+    pub hostname: String,
+    pub tags: Vec<String>,
+    /// The exclude options the backup was made with, as far as they are recorded, in the
+    /// syntax of bacify's (and restic's) arguments
+    pub excludes: Vec<String>,
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let paths = self
+            .paths
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect::<Vec<String>>();
+        write!(
+            f,
+            "{} {} {} {}",
+            self.id,
+            self.time.format("%Y-%m-%d %H:%M:%S"),
+            self.hostname,
+            paths.join(", ")
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Dir,
+    Symlink,
+    Fifo,
+    Socket,
+    BlockDevice,
+    CharDevice,
+    Other,
+}
+
+/// Permissions, ownership and the other timestamps of a node, as far as they are recorded.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Attributes {
+    /// Permission bits, including setuid, setgid and sticky
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub accessed: Option<SystemTime>,
+    pub changed: Option<SystemTime>,
+    /// Extended attributes, POSIX ACLs are stored as `system.posix_acl_*` attributes
+    pub xattrs: Option<Xattrs>,
+}
+
+pub type Xattrs = BTreeMap<String, Vec<u8>>;
+
+/// Device and inode number of a node.
+pub type Inode = (u64, u64);
+
+/// The extended attributes of a local file, without following symlinks.
+pub fn read_xattrs(path: &Path) -> io::Result<Xattrs> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        // The file system doesn't support extended attributes, so there aren't any
+        Err(ref e) if e.kind() == io::ErrorKind::Unsupported => return Ok(Xattrs::new()),
+        Err(e) => return Err(e),
+    };
+    let mut xattrs = Xattrs::new();
+    for name in names {
+        if let Some(value) = xattr::get(path, &name)? {
+            xattrs.insert(name.to_string_lossy().into_owned(), value);
+        }
+    }
+    Ok(xattrs)
+}
+
+impl Attributes {
+    /// Look up the names of the owner, they aren't part of the file system metadata.
+    pub fn with_names(mut self) -> Attributes {
+        self.user = self.uid.and_then(user_name);
+        self.group = self.gid.and_then(group_name);
+        self
+    }
+}
+
+// Call a reentrant lookup of the user or group database, growing the buffer until the entry fits
+fn lookup_name<T>(
+    lookup: impl Fn(*mut T, *mut libc::c_char, usize, *mut *mut T) -> libc::c_int,
+    name: impl Fn(&T) -> *const libc::c_char,
+) -> Option<String> {
+    let mut buffer = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut entry = mem::MaybeUninit::<T>::uninit();
+        let mut result = ptr::null_mut();
+        let error = lookup(
+            entry.as_mut_ptr(),
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        );
+        if error == libc::ERANGE {
+            buffer.resize(buffer.len() * 2, 0);
+        } else if error != 0 || result.is_null() {
+            return None;
+        } else {
+            // SAFETY: The lookup succeeded, so the entry is initialized and its name points into
+            // the buffer, which outlives this borrow
+            let name = unsafe { CStr::from_ptr(name(entry.assume_init_ref())) };
+            return Some(name.to_string_lossy().into_owned());
+        }
+    }
+}
+
+fn user_name(uid: u32) -> Option<String> {
+    // SAFETY: The pointers are valid for the duration of the call and the length matches the buffer
+    lookup_name(
+        |entry, buffer, length, result| unsafe {
+            libc::getpwuid_r(uid, entry, buffer, length, result)
+        },
+        |passwd: &libc::passwd| passwd.pw_name,
+    )
+}
+
+fn group_name(gid: u32) -> Option<String> {
+    // SAFETY: The pointers are valid for the duration of the call and the length matches the buffer
+    lookup_name(
+        |entry, buffer, length, result| unsafe {
+            libc::getgrgid_r(gid, entry, buffer, length, result)
+        },
+        |group: &libc::group| group.gr_name,
+    )
+}
+
+impl From<&fs::Metadata> for Attributes {
+    fn from(metadata: &fs::Metadata) -> Attributes {
+        let time = |seconds: i64, nanos: i64| {
+            let since_epoch = Duration::new(seconds.max(0) as u64, nanos as u32);
+            Some(SystemTime::UNIX_EPOCH + since_epoch)
+        };
+        Attributes {
+            mode: Some(metadata.mode() & 0o7777),
+            uid: Some(metadata.uid()),
+            gid: Some(metadata.gid()),
+            user: None,
+            group: None,
+            accessed: time(metadata.atime(), metadata.atime_nsec()),
+            changed: time(metadata.ctime(), metadata.ctime_nsec()),
+            // Reading them needs the path, see read_xattrs
+            xattrs: None,
+        }
+    }
+}
+
+/// A node of a snapshot as seen by the verifier.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub kind: Kind,
+    pub modified: SystemTime,
+    pub size: u64,
+    pub attributes: Attributes,
+    /// Target of a symlink, if the backup records it
+    pub target: Option<PathBuf>,
+    /// Hard links of each other share it, only meaningful within the same backup
+    pub inode: Option<Inode>,
+    /// Device number of a device node, if the backup records it
+    pub rdev: Option<u64>,
+}
+
+impl Entry {
+    /// The entry of a local file, without following symlinks.
+    pub fn read(path: &Path) -> io::Result<Entry> {
+        let mut entry = Entry::from(fs::symlink_metadata(path)?);
+        if entry.kind == Kind::Symlink {
+            entry.target = Some(fs::read_link(path)?);
+        }
+        Ok(entry)
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.kind == Kind::File
+    }
+}
+
+impl From<fs::FileType> for Kind {
+    fn from(file_type: fs::FileType) -> Kind {
+        if file_type.is_file() {
+            Kind::File
+        } else if file_type.is_dir() {
+            Kind::Dir
+        } else if file_type.is_symlink() {
+            Kind::Symlink
+        } else if file_type.is_fifo() {
+            Kind::Fifo
+        } else if file_type.is_socket() {
+            Kind::Socket
+        } else if file_type.is_block_device() {
+            Kind::BlockDevice
+        } else if file_type.is_char_device() {
+            Kind::CharDevice
+        } else {
+            Kind::Other
+        }
+    }
+}
+
+impl From<fs::Metadata> for Entry {
+    fn from(metadata: fs::Metadata) -> Entry {
+        let kind = Kind::from(metadata.file_type());
+        let is_device = matches!(kind, Kind::BlockDevice | Kind::CharDevice);
+        Entry {
+            kind,
+            // Not all platforms support mtime, treat those files as never modified
+            modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            size: metadata.len(),
+            attributes: Attributes::from(&metadata),
+            // Reading it needs the path, see Entry::read
+            target: None,
+            inode: Some((metadata.dev(), metadata.ino())),
+            rdev: is_device.then(|| metadata.rdev()),
+        }
+    }
+}
+
+/// A backup tool that stores snapshots of the source directory, e.g. restic.
+pub trait Backend {
+    /// All snapshots in the repository, in no particular order.
+    fn snapshots(&self) -> Result<Vec<Snapshot>, Box<dyn Error>>;
+
+    /// Fill in metadata that listing the snapshots doesn't provide.
+    fn details(&self, snapshot: Snapshot) -> Result<Snapshot, Box<dyn Error>> {
+        Ok(snapshot)
+    }
+
+    /// Whether the contents of a snapshot are stored relative to its paths, as with
+    /// `--relative-path`.
+    fn relative_paths(&self) -> bool {
+        false
+    }
+
+    /// Log some information about the snapshot.
+    fn stats(&self, _snapshot: &Snapshot) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// Make the files of the snapshot available for comparison, either by restoring them
+    /// somewhere or by reading them straight from the repository.
+    fn open(&self, snapshot: &Snapshot) -> Result<Box<dyn Contents>, Box<dyn Error>>;
+}
+
+/// The files of an opened snapshot. Paths are relative to the root of the snapshot, i.e.
+/// `home/user/file` for `/home/user/file`.
+pub trait Contents {
+    /// Metadata of `path`, or `None` if it is not in the snapshot.
+    fn metadata(&self, path: &Path) -> io::Result<Option<Entry>>;
+
+    /// The contents of the file at `path`.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>>;
+
+    /// Everything in the snapshot, with paths relative to its root.
+    fn entries(&self) -> io::Result<Vec<(PathBuf, Entry)>>;
+
+    fn sha256(&self, path: &Path) -> io::Result<[u8; 32]> {
+        sha256(&mut self.open(path)?)
+    }
+
+    /// Whether the file at `path` has the same contents as the local `file`.
+    fn same_content(&self, path: &Path, file: &Path) -> io::Result<bool> {
+        Ok(sha256(&mut fs::File::open(file)?)? == self.sha256(path)?)
+    }
+
+    /// Whether the backup records extended attributes at all.
+    fn records_xattrs(&self) -> bool {
+        true
+    }
+
+    /// The extended attributes of `path`, or `None` if the backup doesn't record them.
+    fn xattrs(&self, path: &Path) -> io::Result<Option<Xattrs>> {
+        Ok(self
+            .metadata(path)?
+            .and_then(|entry| entry.attributes.xattrs))
+    }
+
+    /// Modification times are only stored with this resolution, e.g. one second in tar archives.
+    fn time_resolution(&self) -> Duration {
+        Duration::from_nanos(1)
+    }
+}
+
+pub fn sha256(reader: &mut dyn Read) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    io::copy(reader, &mut hasher)?;
+    let hash = hasher.finalize();
+    Ok(hash.into())
+}
+
+/// Round `time` down to a multiple of `resolution`.
+pub fn truncate(time: SystemTime, resolution: Duration) -> SystemTime {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since_epoch) => {
+            let excess = since_epoch.as_nanos() % resolution.as_nanos().max(1);
+            // The excess is smaller than the resolution, so it always fits
+            time - Duration::from_nanos(excess as u64)
+        }
+        Err(_) => time,
+    }
+}
+
+/// Selects the snapshot to verify, with the same options restic uses to filter snapshots.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    /// Prefix of the snapshot id
+    pub id: Option<String>,
+    /// Any of these hosts
+    pub hosts: Vec<String>,
+    /// Any of these comma separated lists of tags, all tags of a list have to match
+    pub tags: Vec<String>,
+    /// All of these paths
+    pub paths: Vec<PathBuf>,
+}
+
+impl Filter {
+    fn matches(&self, snapshot: &Snapshot) -> bool {
+        let id = match &self.id {
+            Some(id) => snapshot.id.starts_with(id.as_str()),
+            None => true,
+        };
+        let host = self.hosts.is_empty() || self.hosts.contains(&snapshot.hostname);
+        let tags = self.tags.is_empty()
+            || self.tags.iter().any(|tags| {
+                tags.split(',')
+                    .all(|tag| tag.is_empty() || snapshot.tags.iter().any(|t| t == tag))
+            });
+        let paths = self.paths.iter().all(|path| snapshot.paths.contains(path));
+        id && host && tags && paths
+    }
+
+    /// The latest matching snapshot. Fails if snapshots of several hosts or sets of paths
+    /// match, as it isn't clear which one should be verified.
+    pub fn select(&self, snapshots: Vec<Snapshot>) -> Result<Snapshot, Box<dyn Error>> {
+        // Not every backend records hosts and tags, e.g. borg and archives don't
+        if !self.hosts.is_empty() && snapshots.iter().all(|s| s.hostname.is_empty()) {
+            return Err("The snapshots don't record a host, --host can't be used".into());
+        }
+        if !self.tags.is_empty() && snapshots.iter().all(|s| s.tags.is_empty()) {
+            return Err("None of the snapshots have tags, --tag can't be used".into());
+        }
+
+        let mut candidates = snapshots
+            .into_iter()
+            .filter(|snapshot| self.matches(snapshot))
+            .collect::<Vec<Snapshot>>();
+        candidates.sort_by_key(|snapshot| snapshot.time);
+
+        if self.id.is_none() {
+            // Only keep the latest snapshot of every host and set of paths
+            let mut latest: Vec<Snapshot> = Vec::new();
+            for snapshot in candidates.into_iter().rev() {
+                let mut paths = snapshot.paths.clone();
+                paths.sort();
+                let seen = latest.iter().any(|other| {
+                    let mut other_paths = other.paths.clone();
+                    other_paths.sort();
+                    other.hostname == snapshot.hostname && other_paths == paths
+                });
+                if !seen {
+                    latest.push(snapshot);
+                }
+            }
+            candidates = latest;
+        }
+
+        match candidates.len() {
+            0 => Err("No matching snapshot found".into()),
+            1 => Ok(candidates.remove(0)),
+            _ => {
+                let listing = candidates
+                    .iter()
+                    .map(|snapshot| format!("  {}", snapshot))
+                    .collect::<Vec<String>>()
+                    .join("\n");
+                Err(format!(
+                    "Several snapshots match, select one with --snapshot, --host, --tag or --path:\n{}",
+                    listing
+                )
+                .into())
+            }
+        }
+    }
+}
+
+/// Snapshot contents that are available as a directory on the local file system.
+pub struct Directory {
+    root: PathBuf,
+    // Restored snapshots live in a temporary directory which is removed on drop
+    _temp_dir: Option<TempDir>,
+}
+
+impl Directory {
+    pub fn new(root: PathBuf) -> Directory {
+        Directory {
+            root,
+            _temp_dir: None,
+        }
+    }
+
+    pub fn temporary(temp_dir: TempDir) -> Directory {
+        Directory {
+            root: temp_dir.path().to_owned(),
+            _temp_dir: Some(temp_dir),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    fn entry(path: &Path) -> io::Result<Entry> {
+        let mut entry = Entry::read(path)?;
+        // The ctime of a copy is when it was made, it can't be restored
+        entry.attributes.changed = None;
+        Ok(entry)
+    }
+}
+
+impl Contents for Directory {
+    fn metadata(&self, path: &Path) -> io::Result<Option<Entry>> {
+        match Directory::entry(&self.root.join(path)) {
+            Ok(entry) => Ok(Some(entry)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        Ok(Box::new(fs::File::open(self.root.join(path))?))
+    }
+
+    fn xattrs(&self, path: &Path) -> io::Result<Option<Xattrs>> {
+        read_xattrs(&self.root.join(path)).map(Some)
+    }
+
+    fn entries(&self) -> io::Result<Vec<(PathBuf, Entry)>> {
+        let mut entries = Vec::new();
+        for entry in WalkDir::new(&self.root).min_depth(1) {
+            let entry = entry?;
+            let path = entry
+                .path()
+                .strip_prefix(&self.root)
+                .unwrap_or(entry.path());
+            entries.push((path.to_owned(), Directory::entry(entry.path())?));
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(id: &str, time: &str, hostname: &str, paths: &[&str], tags: &[&str]) -> Snapshot {
+        Snapshot {
+            id: id.to_owned(),
+            time: DateTime::parse_from_rfc3339(time).unwrap(),
+            paths: paths.iter().map(PathBuf::from).collect(),
+            hostname: hostname.to_owned(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            excludes: Vec::new(),
+        }
+    }
+
+    fn snapshots() -> Vec<Snapshot> {
+        vec![
+            snapshot(
+                "a1",
+                "2024-04-01T12:00:00Z",
+                "laptop",
+                &["/home"],
+                &["daily"],
+            ),
+            snapshot("a2", "2024-04-02T12:00:00Z", "laptop", &["/home"], &[]),
+            snapshot(
+                "b1",
+                "2024-04-03T12:00:00Z",
+                "server",
+                &["/etc", "/srv"],
+                &["daily", "prod"],
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_select_ambiguous() {
+        let error = Filter::default().select(snapshots()).unwrap_err();
+        assert!(error.to_string().contains("a2 "));
+        assert!(error.to_string().contains("b1 "));
+    }
+
+    #[test]
+    fn test_select_latest_of_host() -> Result<(), Box<dyn Error>> {
+        let filter = Filter {
+            hosts: vec!["laptop".into()],
+            ..Default::default()
+        };
+        assert_eq!(filter.select(snapshots())?.id, "a2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_by_tags_and_path() -> Result<(), Box<dyn Error>> {
+        let filter = Filter {
+            tags: vec!["daily,prod".into(), "weekly".into()],
+            ..Default::default()
+        };
+        assert_eq!(filter.select(snapshots())?.id, "b1");
+
+        let filter = Filter {
+            tags: vec!["daily".into()],
+            paths: vec!["/home".into()],
+            ..Default::default()
+        };
+        assert_eq!(filter.select(snapshots())?.id, "a1");
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_unrecorded_host_and_tags() {
+        let snapshots = || vec![snapshot("c1", "2024-04-01T12:00:00Z", "", &["/home"], &[])];
+        let filter = Filter {
+            hosts: vec!["laptop".into()],
+            ..Default::default()
+        };
+        let error = filter.select(snapshots()).unwrap_err();
+        assert!(error.to_string().contains("--host"));
+        let filter = Filter {
+            tags: vec!["daily".into()],
+            ..Default::default()
+        };
+        let error = filter.select(snapshots()).unwrap_err();
+        assert!(error.to_string().contains("--tag"));
+    }
+
+    #[test]
+    fn test_select_by_id() -> Result<(), Box<dyn Error>> {
+        let filter = Filter {
+            id: Some("b".into()),
+            ..Default::default()
+        };
+        assert_eq!(filter.select(snapshots())?.id, "b1");
+
+        let filter = Filter {
+            id: Some("a".into()),
+            ..Default::default()
+        };
+        assert!(filter.select(snapshots()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_directory_contents() -> io::Result<()> {
+        let temp_dir = tempfile::TempDir::with_prefix("bacify-test-")?;
+        fs::create_dir(temp_dir.path().join("dir"))?;
+        fs::write(temp_dir.path().join("dir/file"), "foo")?;
+        std::os::unix::fs::symlink("file", temp_dir.path().join("dir/link"))?;
+        let contents = Directory::temporary(temp_dir);
+
+        let entry = contents.metadata(Path::new("dir/file"))?.unwrap();
+        assert!(entry.is_file());
+        assert_eq!(
+            contents.metadata(Path::new("dir"))?.unwrap().kind,
+            Kind::Dir
+        );
+        let link = contents.metadata(Path::new("dir/link"))?.unwrap();
+        assert_eq!(link.kind, Kind::Symlink);
+        assert_eq!(link.target, Some(PathBuf::from("file")));
+        assert!(contents.metadata(Path::new("nonexistent"))?.is_none());
+        assert_eq!(
+            contents.sha256(Path::new("dir/file"))?,
+            sha256(&mut "foo".as_bytes())?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_attributes_with_names() {
+        let attributes = Attributes {
+            uid: Some(0),
+            gid: Some(u32::MAX - 1),
+            ..Default::default()
+        }
+        .with_names();
+        assert_eq!(attributes.user.as_deref(), Some("root"));
+        assert_eq!(attributes.group, None);
+    }
+}
@@ -0,0 +1,462 @@
+use super::{Attributes, Backend, Contents, Entry, Kind, Snapshot, Xattrs};
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, TimeZone};
+use log::info;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A tar or zip archive of the source directory. The archive is read as is, nothing is
+/// extracted to disk.
+pub struct Archive {
+    path: PathBuf,
+    source: PathBuf,
+    time: Option<DateTime<FixedOffset>>,
+}
+
+impl Archive {
+    /// Without a time the modification time of the archive is used as backup time.
+    pub fn new(path: PathBuf, source: PathBuf, time: Option<DateTime<FixedOffset>>) -> Archive {
+        Archive { path, source, time }
+    }
+}
+
+impl Backend for Archive {
+    fn snapshots(&self) -> Result<Vec<Snapshot>, Box<dyn Error>> {
+        let time = match self.time {
+            Some(time) => time,
+            None => DateTime::<Local>::from(fs::metadata(&self.path)?.modified()?).fixed_offset(),
+        };
+
+        Ok(vec![Snapshot {
+            id: self.path.display().to_string(),
+            time,
+            paths: vec![self.source.clone()],
+            hostname: String::new(),
+            tags: Vec::new(),
+            excludes: Vec::new(),
+        }])
+    }
+
+    fn open(&self, _snapshot: &Snapshot) -> Result<Box<dyn Contents>, Box<dyn Error>> {
+        let name = self
+            .path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or("Invalid archive name")?;
+        let file = fs::File::open(&self.path)?;
+
+        if name.ends_with(".zip") {
+            Ok(Box::new(Zip::new(file)?))
+        } else if name.ends_with(".tar") {
+            Ok(Box::new(Tar::read(file)?))
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Ok(Box::new(Tar::read(flate2::read::MultiGzDecoder::new(
+                file,
+            ))?))
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Ok(Box::new(Tar::read(zstd::Decoder::new(file)?)?))
+        } else {
+            Err(format!("Unsupported archive format: {}", name).into())
+        }
+    }
+}
+
+// Archives may contain paths like `./dir/file` or `/dir/file`, only keep the `dir/file` part
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+// Linux's encoding of a device number, as in st_rdev
+fn makedev(major: u32, minor: u32) -> u64 {
+    let (major, minor) = (u64::from(major), u64::from(minor));
+    ((major & 0xfffff000) << 32)
+        | ((major & 0xfff) << 8)
+        | ((minor & 0xffffff00) << 12)
+        | (minor & 0xff)
+}
+
+/// Contents of a tar archive. Compressed tar archives can only be read front to back, so
+/// every file is hashed while reading the archive once.
+struct Tar {
+    entries: HashMap<PathBuf, (Entry, Option<[u8; 32]>)>,
+    // Whether the archive was created with extended attributes
+    xattrs: bool,
+}
+
+impl Tar {
+    fn read(reader: impl Read) -> io::Result<Tar> {
+        info!("Reading archive...");
+        let mut entries = HashMap::new();
+        let mut archive = tar::Archive::new(reader);
+
+        for (index, entry) in archive.entries()?.enumerate() {
+            let mut entry = entry?;
+            let path = normalize(&entry.path()?);
+            let header = entry.header().clone();
+            let modified = UNIX_EPOCH + Duration::from_secs(header.mtime()?);
+            let size = header.size()?;
+            let attributes = Attributes {
+                mode: header.mode().ok().map(|mode| mode & 0o7777),
+                uid: header.uid().ok().map(|uid| uid as u32),
+                gid: header.gid().ok().map(|gid| gid as u32),
+                user: header.username().ok().flatten().map(String::from),
+                group: header.groupname().ok().flatten().map(String::from),
+                accessed: None,
+                changed: None,
+                xattrs: Tar::xattrs(&mut entry)?,
+            };
+
+            let record = match header.entry_type() {
+                tar::EntryType::Regular | tar::EntryType::Continuous => (
+                    Entry {
+                        kind: Kind::File,
+                        modified,
+                        size,
+                        attributes,
+                        target: None,
+                        // Tar has no inodes, but hard links copy the entry including this
+                        inode: Some((0, index as u64)),
+                        rdev: None,
+                    },
+                    Some(super::sha256(&mut entry)?),
+                ),
+                // Hard links share the contents of a previous entry
+                tar::EntryType::Link => {
+                    let target = entry
+                        .link_name()?
+                        .map(|target| normalize(&target))
+                        .and_then(|target| entries.get(&target).cloned());
+                    match target {
+                        Some(record) => record,
+                        None => continue,
+                    }
+                }
+                tar::EntryType::Directory => (
+                    Entry {
+                        kind: Kind::Dir,
+                        modified,
+                        size,
+                        attributes,
+                        target: None,
+                        inode: None,
+                        rdev: None,
+                    },
+                    None,
+                ),
+                tar::EntryType::Symlink => (
+                    Entry {
+                        kind: Kind::Symlink,
+                        modified,
+                        size,
+                        attributes,
+                        target: entry.link_name()?.map(|target| target.into_owned()),
+                        inode: None,
+                        rdev: None,
+                    },
+                    None,
+                ),
+                entry_type => {
+                    let kind = match entry_type {
+                        tar::EntryType::Fifo => Kind::Fifo,
+                        tar::EntryType::Block => Kind::BlockDevice,
+                        tar::EntryType::Char => Kind::CharDevice,
+                        _ => Kind::Other,
+                    };
+                    let major = header.device_major().ok().flatten();
+                    let minor = header.device_minor().ok().flatten();
+                    let rdev = match (major, minor) {
+                        (Some(major), Some(minor))
+                            if matches!(kind, Kind::BlockDevice | Kind::CharDevice) =>
+                        {
+                            Some(makedev(major, minor))
+                        }
+                        _ => None,
+                    };
+                    (
+                        Entry {
+                            kind,
+                            modified,
+                            size,
+                            attributes,
+                            target: None,
+                            inode: None,
+                            rdev,
+                        },
+                        None,
+                    )
+                }
+            };
+            entries.insert(path, record);
+        }
+
+        // Only entries that have extended attributes record them
+        let xattrs = entries
+            .values()
+            .any(|(entry, _)| entry.attributes.xattrs.is_some());
+        if xattrs {
+            for (entry, _) in entries.values_mut() {
+                entry.attributes.xattrs.get_or_insert_default();
+            }
+        }
+
+        Ok(Tar { entries, xattrs })
+    }
+}
+
+impl Tar {
+    // GNU tar and bsdtar store extended attributes as PAX extensions, if asked to
+    fn xattrs<R: Read>(entry: &mut tar::Entry<R>) -> io::Result<Option<Xattrs>> {
+        let Some(extensions) = entry.pax_extensions()? else {
+            return Ok(None);
+        };
+        let mut xattrs = Xattrs::new();
+        for extension in extensions {
+            let extension = extension?;
+            if let Some(name) = extension
+                .key()
+                .ok()
+                .and_then(|key| key.strip_prefix("SCHILY.xattr."))
+            {
+                xattrs.insert(name.to_owned(), extension.value_bytes().to_vec());
+            }
+        }
+        Ok((!xattrs.is_empty()).then_some(xattrs))
+    }
+}
+
+impl Contents for Tar {
+    fn metadata(&self, path: &Path) -> io::Result<Option<Entry>> {
+        Ok(self.entries.get(path).map(|(entry, _)| entry.clone()))
+    }
+
+    fn open(&self, _path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Tar archives can't be read in random order",
+        ))
+    }
+
+    fn sha256(&self, path: &Path) -> io::Result<[u8; 32]> {
+        self.entries
+            .get(path)
+            .and_then(|(_, sha256)| *sha256)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn records_xattrs(&self) -> bool {
+        self.xattrs
+    }
+
+    fn time_resolution(&self) -> Duration {
+        Duration::from_secs(1)
+    }
+
+    fn entries(&self) -> io::Result<Vec<(PathBuf, Entry)>> {
+        Ok(self
+            .entries
+            .iter()
+            .map(|(path, (entry, _))| (path.clone(), entry.clone()))
+            .collect())
+    }
+}
+
+/// Contents of a zip archive, which can be read in random order.
+struct Zip {
+    archive: RefCell<zip::ZipArchive<fs::File>>,
+    entries: HashMap<PathBuf, (Entry, String)>,
+}
+
+impl Zip {
+    fn new(file: fs::File) -> io::Result<Zip> {
+        let mut archive = zip::ZipArchive::new(file)?;
+        let mut entries = HashMap::new();
+
+        for index in 0..archive.len() {
+            let file = archive.by_index_raw(index)?;
+            let kind = if file.is_dir() {
+                Kind::Dir
+            } else if file.is_symlink() {
+                Kind::Symlink
+            } else if file.is_file() {
+                Kind::File
+            } else {
+                Kind::Other
+            };
+            let mut entry = Entry {
+                kind,
+                modified: Zip::modified(&file).unwrap_or(UNIX_EPOCH),
+                size: file.size(),
+                attributes: Attributes {
+                    mode: file.unix_mode().map(|mode| mode & 0o7777),
+                    ..Default::default()
+                },
+                target: None,
+                inode: None,
+                rdev: None,
+            };
+            let name = file.name().to_owned();
+            drop(file);
+            // The target of a symlink is stored as its contents
+            if entry.kind == Kind::Symlink {
+                let mut target = String::new();
+                archive.by_index(index)?.read_to_string(&mut target)?;
+                entry.target = Some(PathBuf::from(target));
+            }
+            entries.insert(normalize(Path::new(&name)), (entry, name));
+        }
+
+        Ok(Zip {
+            archive: RefCell::new(archive),
+            entries,
+        })
+    }
+
+    // Prefer the UTC extended timestamp over the MS-DOS timestamp in local time
+    fn modified(file: &zip::read::ZipFile) -> Option<SystemTime> {
+        let extended = file.extra_data_fields().find_map(|field| match field {
+            zip::ExtraField::ExtendedTimestamp(timestamp) => timestamp.mod_time(),
+            _ => None,
+        });
+        if let Some(seconds) = extended {
+            return Some(UNIX_EPOCH + Duration::from_secs(seconds.into()));
+        }
+
+        let dos = file.last_modified()?;
+        let naive =
+            NaiveDate::from_ymd_opt(dos.year().into(), dos.month().into(), dos.day().into())?
+                .and_hms_opt(dos.hour().into(), dos.minute().into(), dos.second().into())?;
+        Local
+            .from_local_datetime(&naive)
+            .earliest()
+            .map(SystemTime::from)
+    }
+}
+
+impl Contents for Zip {
+    fn metadata(&self, path: &Path) -> io::Result<Option<Entry>> {
+        Ok(self.entries.get(path).map(|(entry, _)| entry.clone()))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        let (_, name) = self
+            .entries
+            .get(path)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        // The borrowed archive can't outlive this call, so read the file into memory
+        let mut archive = self.archive.borrow_mut();
+        let mut contents = Vec::new();
+        archive.by_name(name)?.read_to_end(&mut contents)?;
+        Ok(Box::new(io::Cursor::new(contents)))
+    }
+
+    fn sha256(&self, path: &Path) -> io::Result<[u8; 32]> {
+        let (_, name) = self
+            .entries
+            .get(path)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let mut archive = self.archive.borrow_mut();
+        let mut file = archive.by_name(name)?;
+        super::sha256(&mut file)
+    }
+
+    fn records_xattrs(&self) -> bool {
+        false
+    }
+
+    fn time_resolution(&self) -> Duration {
+        // MS-DOS timestamps only have a resolution of two seconds
+        Duration::from_secs(2)
+    }
+
+    fn entries(&self) -> io::Result<Vec<(PathBuf, Entry)>> {
+        Ok(self
+            .entries
+            .iter()
+            .map(|(path, (entry, _))| (path.clone(), entry.clone()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_tar() -> io::Result<()> {
+        let mut builder = tar::Builder::new(Vec::new());
+        // Extended attributes of the next entry, as written by `tar --xattrs`
+        let pax = b"25 SCHILY.xattr.user.a=1\n";
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::XHeader);
+        header.set_size(pax.len() as u64);
+        header.set_cksum();
+        builder.append_data(&mut header, "PaxHeaders/file", pax.as_slice())?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(3);
+        header.set_mtime(1_700_000_000);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, "./home/user/file", "foo".as_bytes())?;
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        header.set_mtime(1_700_000_000);
+        builder.append_link(&mut header, "./home/user/link", "file")?;
+        let archive = builder.into_inner()?;
+
+        let tar = Tar::read(archive.as_slice())?;
+        let path = Path::new("home/user/file");
+        let entry = tar.metadata(path)?.unwrap();
+        assert!(entry.is_file());
+        assert_eq!(
+            entry.modified,
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+        );
+        assert_eq!(
+            tar.sha256(path)?,
+            super::super::sha256(&mut "foo".as_bytes())?
+        );
+        let link = tar.metadata(Path::new("home/user/link"))?.unwrap();
+        assert_eq!(link.kind, Kind::Symlink);
+        assert_eq!(link.target, Some(PathBuf::from("file")));
+        assert!(tar.metadata(Path::new("home/user/other"))?.is_none());
+        assert!(tar.records_xattrs());
+        assert_eq!(
+            entry.attributes.xattrs,
+            Some(Xattrs::from([("user.a".to_owned(), b"1".to_vec())]))
+        );
+        assert_eq!(link.attributes.xattrs, Some(Xattrs::new()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_zip() -> Result<(), Box<dyn Error>> {
+        let temp_dir = tempfile::TempDir::with_prefix("bacify-test-")?;
+        let path = temp_dir.path().join("backup.zip");
+        let mut writer = zip::ZipWriter::new(fs::File::create(&path)?);
+        writer.add_directory("dir/", zip::write::SimpleFileOptions::default())?;
+        writer.start_file("dir/file", zip::write::SimpleFileOptions::default())?;
+        writer.write_all(b"foo")?;
+        writer.add_symlink("dir/link", "file", zip::write::SimpleFileOptions::default())?;
+        writer.finish()?;
+
+        let zip = Zip::new(fs::File::open(&path)?)?;
+        assert!(zip.metadata(Path::new("dir/file"))?.unwrap().is_file());
+        assert_eq!(zip.metadata(Path::new("dir"))?.unwrap().kind, Kind::Dir);
+        let link = zip.metadata(Path::new("dir/link"))?.unwrap();
+        assert_eq!(link.kind, Kind::Symlink);
+        assert_eq!(link.target, Some(PathBuf::from("file")));
+        assert_eq!(
+            zip.sha256(Path::new("dir/file"))?,
+            super::super::sha256(&mut "foo".as_bytes())?
+        );
+        Ok(())
+    }
+}
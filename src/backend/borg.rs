@@ -0,0 +1,360 @@
+use super::{Backend, Contents, Directory, Snapshot};
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, TimeZone};
+use log::warn;
+use serde_json::Value;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// The borg command line client, configured via BORG_REPO and BORG_PASSPHRASE.
+pub struct Borg;
+
+// Options of `borg create` that take a value, needed to tell values and paths apart
+const CREATE_VALUE_OPTIONS: &[&str] = &[
+    "-e",
+    "--exclude",
+    "--exclude-from",
+    "--pattern",
+    "--patterns-from",
+    "--exclude-if-present",
+    "--comment",
+    "--timestamp",
+    "-c",
+    "--checkpoint-interval",
+    "--chunker-params",
+    "-C",
+    "--compression",
+    "--files-cache",
+    "--filter",
+    "--stdin-name",
+    "--stdin-user",
+    "--stdin-group",
+    "--stdin-mode",
+    "--paths-delimiter",
+    "--upload-ratelimit",
+    "--upload-buffer",
+    "--remote-path",
+    "--lock-wait",
+    "--umask",
+];
+
+impl Borg {
+    // borg 1.x prints naive local timestamps, newer versions include the offset
+    fn parse_time(time: &str) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(time).ok().or_else(|| {
+            let naive = NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S%.f").ok()?;
+            Local
+                .from_local_datetime(&naive)
+                .single()
+                .map(|t| t.fixed_offset())
+        })
+    }
+
+    fn parse_archives(json: &[u8]) -> Result<Vec<Snapshot>, Box<dyn Error>> {
+        let list: Value = serde_json::from_slice(json)?;
+        let archives = list["archives"]
+            .as_array()
+            .ok_or("No archive data available")?;
+
+        archives
+            .iter()
+            .map(|archive| {
+                let time = archive["start"]
+                    .as_str()
+                    .or(archive["time"].as_str())
+                    .and_then(Borg::parse_time)
+                    .ok_or("Invalid archive time")?;
+                let id = archive["name"]
+                    .as_str()
+                    .map(String::from)
+                    .ok_or("Invalid archive name")?;
+                Ok(Snapshot {
+                    id,
+                    time,
+                    // Paths are filled in by details, there is no hostname in the list
+                    paths: Vec::new(),
+                    hostname: String::new(),
+                    tags: Vec::new(),
+                    excludes: Vec::new(),
+                })
+            })
+            .collect()
+    }
+
+    fn parse_command_line(command_line: &[Value]) -> Result<Vec<&str>, Box<dyn Error>> {
+        Ok(command_line
+            .iter()
+            .map(|arg| arg.as_str())
+            .collect::<Option<Vec<&str>>>()
+            .ok_or("Invalid archive command line")?)
+    }
+
+    // Archives don't list their paths, but they are the arguments following the archive in
+    // the recorded `borg create` command line.
+    fn parse_paths(args: &[&str]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let archive = args
+            .iter()
+            .position(|arg| arg.contains("::"))
+            .ok_or("Couldn't find the archive in the command line")?;
+
+        let mut paths = Vec::new();
+        let mut args = args[archive + 1..].iter();
+        while let Some(arg) = args.next() {
+            if CREATE_VALUE_OPTIONS.contains(arg) {
+                args.next();
+            } else if !arg.starts_with('-') {
+                paths.push(PathBuf::from(arg));
+            }
+        }
+        Ok(paths)
+    }
+
+    // Borg matches patterns against the whole path without the leading slash. Shell (`sh:`) and
+    // path prefix (`pp:`, `pf:`) patterns translate to rooted restic patterns, the default
+    // `fm:` style is close enough, but its `*` also matches across directories.
+    fn convert_pattern(pattern: &str) -> Option<String> {
+        let (style, pattern) = match pattern.split_once(':') {
+            Some((style, pattern)) if style.len() == 2 => (style, pattern),
+            _ => ("fm", pattern),
+        };
+        match style {
+            "fm" | "sh" | "pp" | "pf" if pattern.starts_with('*') => Some(pattern.to_owned()),
+            "fm" | "sh" | "pp" | "pf" => Some(format!("/{}", pattern.trim_start_matches('/'))),
+            _ => {
+                warn!("Can't use borg pattern {:?}", pattern);
+                None
+            }
+        }
+    }
+
+    // The patterns of an exclude file, one per line like on the command line
+    fn read_exclude_file(file: &str) -> Vec<String> {
+        match fs::read_to_string(file) {
+            Ok(contents) => contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .filter_map(Borg::convert_pattern)
+                .collect(),
+            Err(e) => {
+                warn!("Can't read borg exclude file {:?}: {}", file, e);
+                Vec::new()
+            }
+        }
+    }
+
+    // The exclude options in the recorded `borg create` command line
+    fn parse_excludes(args: &[&str]) -> Vec<String> {
+        let mut excludes = Vec::new();
+        // Without this borg leaves out the tag files too, but bacify expects them in the backup
+        let keep_tags = args
+            .iter()
+            .any(|arg| *arg == "--keep-exclude-tags" || *arg == "--keep-tag-files");
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let (option, value) = match arg.split_once('=') {
+                Some((option, value)) if option.starts_with("--") => (option, Some(value)),
+                _ => (*arg, None),
+            };
+            let mut value = || value.or_else(|| args.next().copied());
+            match option {
+                "-e" | "--exclude" => {
+                    if let Some(pattern) = value().and_then(Borg::convert_pattern) {
+                        excludes.extend(["--exclude".to_owned(), pattern]);
+                    }
+                }
+                "--exclude-from" => {
+                    if let Some(file) = value() {
+                        for pattern in Borg::read_exclude_file(file) {
+                            excludes.extend(["--exclude".to_owned(), pattern]);
+                        }
+                    }
+                }
+                "--exclude-if-present" => match value() {
+                    Some(name) if keep_tags => {
+                        excludes.extend(["--exclude-if-present".to_owned(), name.to_owned()]);
+                    }
+                    Some(name) => warn!(
+                        "Can't use borg --exclude-if-present {:?} without --keep-exclude-tags",
+                        name
+                    ),
+                    None => {}
+                },
+                "--exclude-caches" if keep_tags => excludes.push("--exclude-caches".to_owned()),
+                "--exclude-caches" => {
+                    warn!("Can't use borg --exclude-caches without --keep-exclude-tags");
+                }
+                "-x" | "--one-file-system" => excludes.push("--one-file-system".to_owned()),
+                "--pattern" | "--patterns-from" => {
+                    warn!(
+                        "Can't use borg {} {:?}",
+                        option,
+                        value().unwrap_or_default()
+                    );
+                }
+                _ => {}
+            }
+        }
+        excludes
+    }
+}
+
+impl Backend for Borg {
+    fn snapshots(&self) -> Result<Vec<Snapshot>, Box<dyn Error>> {
+        let list = Command::new("borg").args(["list", "--json"]).output()?;
+
+        if list.stdout.is_empty() {
+            return Err(
+                "Couldn't find any archives. Did you set BORG_REPO and BORG_PASSPHRASE? Is borg installed?"
+                    .into(),
+            );
+        }
+
+        Borg::parse_archives(&list.stdout)
+    }
+
+    fn details(&self, mut snapshot: Snapshot) -> Result<Snapshot, Box<dyn Error>> {
+        let info = Command::new("borg")
+            .args(["info", "--json", &format!("::{}", snapshot.id)])
+            .output()?;
+        if !info.status.success() {
+            return Err(format!("Couldn't get info of archive {}", snapshot.id).into());
+        }
+        let info: Value = serde_json::from_slice(&info.stdout)?;
+        let command_line = info["archives"][0]["command_line"]
+            .as_array()
+            .ok_or("Invalid archive command line")?;
+        let args = Borg::parse_command_line(command_line)?;
+        snapshot.paths = Borg::parse_paths(&args)?;
+        snapshot.excludes = Borg::parse_excludes(&args);
+        Ok(snapshot)
+    }
+
+    fn stats(&self, snapshot: &Snapshot) -> Result<(), Box<dyn Error>> {
+        Command::new("borg")
+            .args(["info", &format!("::{}", snapshot.id)])
+            .status()?;
+        Ok(())
+    }
+
+    fn open(&self, snapshot: &Snapshot) -> Result<Box<dyn Contents>, Box<dyn Error>> {
+        let temp_dir = tempfile::TempDir::with_prefix("bacify-")?;
+        let backup_dir = Directory::temporary(temp_dir);
+        // borg extracts into the current directory
+        let status = Command::new("borg")
+            .args(["extract", &format!("::{}", snapshot.id)])
+            .current_dir(backup_dir.path())
+            .status()?;
+        if !status.success() {
+            return Err(format!("Couldn't extract archive {}", snapshot.id).into());
+        }
+        Ok(Box::new(backup_dir))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_archives() -> Result<(), Box<dyn Error>> {
+        let json = br#"{
+            "archives": [
+                {
+                    "archive": "laptop-2024-04-01",
+                    "barchive": "laptop-2024-04-01",
+                    "id": "f5a4b8d3e2c1",
+                    "name": "laptop-2024-04-01",
+                    "start": "2024-04-01T12:00:00.000000",
+                    "time": "2024-04-01T12:00:00.000000"
+                },
+                {
+                    "archive": "laptop-2024-04-02",
+                    "id": "0c8e77a1b2d4",
+                    "name": "laptop-2024-04-02",
+                    "start": "2024-04-02T12:00:00.000000+02:00",
+                    "time": "2024-04-02T12:00:00.000000+02:00"
+                }
+            ],
+            "repository": {"id": "9c3d", "location": "/backup/borg"}
+        }"#;
+
+        let snapshots = Borg::parse_archives(json)?;
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].id, "laptop-2024-04-01");
+        assert_eq!(
+            snapshots[0].time,
+            Local
+                .with_ymd_and_hms(2024, 4, 1, 12, 0, 0)
+                .unwrap()
+                .fixed_offset()
+        );
+        assert_eq!(
+            snapshots[1].time,
+            DateTime::parse_from_rfc3339("2024-04-02T10:00:00Z")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_paths() -> Result<(), Box<dyn Error>> {
+        let command_line: Value = serde_json::from_str(
+            r#"["/usr/bin/borg", "create", "--stats", "-e", "*.pyc", "::laptop-{now}",
+                "/etc", "/home/user", "--exclude-caches"]"#,
+        )?;
+
+        let args = Borg::parse_command_line(command_line.as_array().unwrap())?;
+        let paths = Borg::parse_paths(&args)?;
+        assert_eq!(paths, [PathBuf::from("/etc"), PathBuf::from("/home/user")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_excludes() -> Result<(), Box<dyn Error>> {
+        let temp_dir = tempfile::TempDir::with_prefix("bacify-test-")?;
+        let exclude_file = temp_dir.path().join("excludes");
+        fs::write(&exclude_file, "# Caches\nhome/*/.cache\nsh:**/*.tmp\n")?;
+        let exclude_from = format!("--exclude-from={}", exclude_file.display());
+
+        let args = [
+            "borg",
+            "create",
+            "-e",
+            "*.pyc",
+            "--exclude=home/*/.cache",
+            "-e",
+            "re:^/tmp",
+            "--exclude-if-present",
+            ".nobackup",
+            "--exclude-caches",
+            "--keep-exclude-tags",
+            &exclude_from,
+            "-x",
+            "::{now}",
+            "/home",
+        ];
+        assert_eq!(
+            Borg::parse_excludes(&args),
+            [
+                "--exclude",
+                "*.pyc",
+                "--exclude",
+                "/home/*/.cache",
+                "--exclude-if-present",
+                ".nobackup",
+                "--exclude-caches",
+                "--exclude",
+                "/home/*/.cache",
+                "--exclude",
+                "**/*.tmp",
+                "--one-file-system"
+            ]
+        );
+
+        // The tag files aren't in the backup without --keep-exclude-tags
+        let args = ["borg", "create", "--exclude-caches", "::{now}", "/home"];
+        assert!(Borg::parse_excludes(&args).is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+use super::{Backend, Contents, Directory, Snapshot};
+use chrono::DateTime;
+use serde_json::Value;
+use std::error::Error;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// The kopia command line client, using the repository it is connected to.
+pub struct Kopia;
+
+impl Kopia {
+    fn parse_snapshots(json: &[u8]) -> Result<Vec<Snapshot>, Box<dyn Error>> {
+        let snapshots: Value = serde_json::from_slice(json)?;
+        let snapshots = snapshots.as_array().ok_or("No snapshot data available")?;
+
+        snapshots
+            .iter()
+            .map(|snapshot| {
+                let time = snapshot["startTime"]
+                    .as_str()
+                    .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+                    .ok_or("Invalid snapshot time")?;
+                let id = snapshot["id"]
+                    .as_str()
+                    .map(String::from)
+                    .ok_or("Invalid snapshot id")?;
+                let path = snapshot["source"]["path"]
+                    .as_str()
+                    .map(PathBuf::from)
+                    .ok_or("Invalid source directory")?;
+                let hostname = snapshot["source"]["host"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_owned();
+                // Tags are stored as {"tag:key": "value"}
+                let tags = snapshot["tags"]
+                    .as_object()
+                    .map(|tags| {
+                        tags.iter()
+                            .map(|(key, value)| {
+                                let key = key.strip_prefix("tag:").unwrap_or(key);
+                                format!("{}:{}", key, value.as_str().unwrap_or_default())
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                Ok(Snapshot {
+                    id,
+                    time,
+                    paths: vec![path],
+                    hostname,
+                    tags,
+                    excludes: Vec::new(),
+                })
+            })
+            .collect()
+    }
+}
+
+impl Backend for Kopia {
+    fn snapshots(&self) -> Result<Vec<Snapshot>, Box<dyn Error>> {
+        let snapshot_list = Command::new("kopia")
+            .args(["snapshot", "list", "--json"])
+            .output()?;
+
+        if snapshot_list.stdout.is_empty() {
+            return Err(
+                "Couldn't find any snapshots. Did you connect to the repository? Is kopia installed?"
+                    .into(),
+            );
+        }
+
+        Kopia::parse_snapshots(&snapshot_list.stdout)
+    }
+
+    fn relative_paths(&self) -> bool {
+        // kopia restores the contents of the source directory, not the directory itself
+        true
+    }
+
+    fn open(&self, snapshot: &Snapshot) -> Result<Box<dyn Contents>, Box<dyn Error>> {
+        let temp_dir = tempfile::TempDir::with_prefix("bacify-")?;
+        let backup_dir = Directory::temporary(temp_dir);
+        let status = Command::new("kopia")
+            .args([
+                "snapshot",
+                "restore",
+                &snapshot.id,
+                backup_dir
+                    .path()
+                    .to_str()
+                    .ok_or("Invalid backup directory")?,
+            ])
+            .status()?;
+        if !status.success() {
+            return Err(format!("Couldn't restore snapshot {}", snapshot.id).into());
+        }
+        Ok(Box::new(backup_dir))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_snapshots() -> Result<(), Box<dyn Error>> {
+        let json = br#"[{
+            "id": "k1d4c8e0f1a9b2c3",
+            "source": {"host": "laptop", "userName": "user", "path": "/home/user/dev/bacify"},
+            "description": "",
+            "tags": {"tag:env": "dev"},
+            "startTime": "2024-04-01T10:00:00.123456789Z",
+            "endTime": "2024-04-01T10:00:05.5Z",
+            "stats": {"totalSize": 1234, "fileCount": 12},
+            "rootEntry": {"name": "bacify", "type": "d", "obj": "k7a1"}
+        }]"#;
+
+        let snapshots = Kopia::parse_snapshots(json)?;
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].id, "k1d4c8e0f1a9b2c3");
+        assert_eq!(
+            snapshots[0].time,
+            DateTime::parse_from_rfc3339("2024-04-01T10:00:00.123456789Z")?
+        );
+        assert_eq!(snapshots[0].paths, [PathBuf::from("/home/user/dev/bacify")]);
+        assert_eq!(snapshots[0].hostname, "laptop");
+        assert_eq!(snapshots[0].tags, ["env:dev"]);
+        Ok(())
+    }
+}
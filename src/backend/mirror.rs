@@ -0,0 +1,43 @@
+use super::{Backend, Contents, Directory, Snapshot};
+use chrono::{DateTime, FixedOffset};
+use std::error::Error;
+use std::path::PathBuf;
+
+/// A plain copy of the source directory, e.g. made by rsync or `rclone copy`. It is compared
+/// in place, so nothing is restored.
+pub struct Mirror {
+    dir: PathBuf,
+    source: PathBuf,
+    time: DateTime<FixedOffset>,
+}
+
+impl Mirror {
+    pub fn new(dir: PathBuf, source: PathBuf, time: DateTime<FixedOffset>) -> Mirror {
+        Mirror { dir, source, time }
+    }
+}
+
+impl Backend for Mirror {
+    fn snapshots(&self) -> Result<Vec<Snapshot>, Box<dyn Error>> {
+        if !self.dir.is_dir() {
+            return Err(format!("Couldn't find mirror directory {:?}", self.dir).into());
+        }
+
+        Ok(vec![Snapshot {
+            id: self.dir.display().to_string(),
+            time: self.time,
+            paths: vec![self.source.clone()],
+            hostname: String::new(),
+            tags: Vec::new(),
+            excludes: Vec::new(),
+        }])
+    }
+
+    fn relative_paths(&self) -> bool {
+        true
+    }
+
+    fn open(&self, _snapshot: &Snapshot) -> Result<Box<dyn Contents>, Box<dyn Error>> {
+        Ok(Box::new(Directory::new(self.dir.clone())))
+    }
+}
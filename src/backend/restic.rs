@@ -0,0 +1,383 @@
+use super::{Attributes, Backend, Contents, Directory, Entry, Kind, Snapshot};
+use base64::prelude::*;
+use chrono::DateTime;
+use log::info;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::time::SystemTime;
+
+mod chunker;
+mod repository;
+
+pub use repository::Repository;
+
+/// The restic command line client, configured via RESTIC_REPOSITORY and RESTIC_PASSWORD.
+pub struct Restic {
+    stream: bool,
+}
+
+impl Restic {
+    /// With `stream` the snapshot isn't restored, instead the files are listed with `restic ls`
+    /// and only the ones that need to be compared are read with `restic dump`.
+    pub fn new(stream: bool) -> Restic {
+        Restic { stream }
+    }
+
+    fn parse_snapshots(json: &[u8]) -> Result<Vec<Snapshot>, Box<dyn Error>> {
+        let snapshots: Value = serde_json::from_slice(json)?;
+        let snapshots = snapshots.as_array().ok_or("No snapshot data available")?;
+
+        snapshots
+            .iter()
+            .map(|snapshot| {
+                let id = snapshot["id"].as_str().ok_or("Invalid snapshot id")?;
+                parse_snapshot(snapshot, id)
+            })
+            .collect()
+    }
+}
+
+// The JSON representation of a snapshot, without the id as it is the name of the snapshot file
+fn parse_snapshot(snapshot: &Value, id: &str) -> Result<Snapshot, Box<dyn Error>> {
+    let time = snapshot["time"]
+        .as_str()
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        .ok_or("Invalid snapshot time")?;
+    let paths = snapshot["paths"]
+        .as_array()
+        .ok_or("Invalid source directory")?
+        .iter()
+        .map(|path| path.as_str().map(PathBuf::from))
+        .collect::<Option<Vec<PathBuf>>>()
+        .ok_or("Invalid source directory")?;
+    let hostname = snapshot["hostname"].as_str().unwrap_or_default().to_owned();
+    let tags = snapshot["tags"]
+        .as_array()
+        .map(|tags| {
+            tags.iter()
+                .filter_map(|tag| tag.as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default();
+    // Only the --exclude patterns are recorded, not exclude files or other options
+    let excludes = snapshot["excludes"]
+        .as_array()
+        .map(|excludes| {
+            excludes
+                .iter()
+                .filter_map(|exclude| exclude.as_str())
+                .flat_map(|exclude| ["--exclude".to_owned(), exclude.to_owned()])
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(Snapshot {
+        id: id.to_owned(),
+        time,
+        paths,
+        hostname,
+        tags,
+        excludes,
+    })
+}
+
+// The JSON representation of a node in a tree, as stored in the repository and printed by `ls`
+fn parse_node(node: &Value) -> Option<Entry> {
+    let kind = match node["type"].as_str()? {
+        "file" => Kind::File,
+        "dir" => Kind::Dir,
+        "symlink" => Kind::Symlink,
+        "fifo" => Kind::Fifo,
+        "socket" => Kind::Socket,
+        "dev" => Kind::BlockDevice,
+        "chardev" => Kind::CharDevice,
+        _ => Kind::Other,
+    };
+    let modified = node["mtime"]
+        .as_str()
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        .map(SystemTime::from)?;
+    let size = node["size"].as_u64().unwrap_or(0);
+    Some(Entry {
+        kind,
+        modified,
+        size,
+        attributes: parse_attributes(node),
+        target: node["linktarget"].as_str().map(PathBuf::from),
+        // The device is only recorded for hard links
+        inode: node["inode"]
+            .as_u64()
+            .map(|inode| (node["device_id"].as_u64().unwrap_or(0), inode)),
+        rdev: node["device"].as_u64(),
+    })
+}
+
+fn parse_attributes(node: &Value) -> Attributes {
+    let time = |field: &str| {
+        node[field]
+            .as_str()
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+            .map(SystemTime::from)
+    };
+    // Go's file mode has its own bits for setuid, setgid and sticky
+    let mode = node["mode"].as_u64().map(|mode| {
+        let mut permissions = (mode & 0o777) as u32;
+        for (go, unix) in [(1 << 23, 0o4000), (1 << 22, 0o2000), (1 << 20, 0o1000)] {
+            if mode & go != 0 {
+                permissions |= unix;
+            }
+        }
+        permissions
+    });
+    Attributes {
+        mode,
+        uid: node["uid"].as_u64().map(|uid| uid as u32),
+        gid: node["gid"].as_u64().map(|gid| gid as u32),
+        user: node["user"].as_str().map(String::from),
+        group: node["group"].as_str().map(String::from),
+        accessed: time("atime"),
+        changed: time("ctime"),
+        // Not part of the output of `restic ls`, only of the trees in the repository
+        xattrs: node["extended_attributes"].as_array().map(|xattrs| {
+            xattrs
+                .iter()
+                .filter_map(|xattr| {
+                    let name = xattr["name"].as_str()?;
+                    let value = BASE64_STANDARD
+                        .decode(xattr["value"].as_str().unwrap_or_default())
+                        .ok()?;
+                    Some((name.to_owned(), value))
+                })
+                .collect()
+        }),
+    }
+}
+
+impl Backend for Restic {
+    fn snapshots(&self) -> Result<Vec<Snapshot>, Box<dyn Error>> {
+        let snapshot_info = Command::new("restic")
+            .args(["snapshots", "--json"])
+            .output()?;
+
+        if snapshot_info.stdout.is_empty() {
+            return Err(
+                "Couldn't find any snapshots. Did you set RESTIC_REPOSITORY and RESTIC_PASSWORD? Is restic installed?"
+                    .into(),
+            );
+        }
+
+        Restic::parse_snapshots(&snapshot_info.stdout)
+    }
+
+    fn stats(&self, snapshot: &Snapshot) -> Result<(), Box<dyn Error>> {
+        Command::new("restic")
+            .arg("stats")
+            .arg(&snapshot.id)
+            .status()?;
+        Ok(())
+    }
+
+    fn open(&self, snapshot: &Snapshot) -> Result<Box<dyn Contents>, Box<dyn Error>> {
+        if self.stream {
+            return Ok(Box::new(Listing::new(snapshot)?));
+        }
+
+        let temp_dir = tempfile::TempDir::with_prefix("bacify-")?;
+        let backup_dir = Directory::temporary(temp_dir);
+        let status = Command::new("restic")
+            .args([
+                "restore",
+                &snapshot.id,
+                "--target",
+                backup_dir
+                    .path()
+                    .to_str()
+                    .ok_or("Invalid backup directory")?,
+            ])
+            .status()?;
+        if !status.success() {
+            return Err(format!("Couldn't restore snapshot {}", snapshot.id).into());
+        }
+        Ok(Box::new(backup_dir))
+    }
+}
+
+/// The nodes of a snapshot as listed by `restic ls`, file contents are read on demand.
+struct Listing {
+    id: String,
+    entries: HashMap<PathBuf, Entry>,
+}
+
+impl Listing {
+    fn new(snapshot: &Snapshot) -> Result<Listing, Box<dyn Error>> {
+        info!("Listing snapshot {}...", snapshot.id);
+        let ls = Command::new("restic")
+            .args(["ls", "--json", &snapshot.id])
+            .output()?;
+        if !ls.status.success() {
+            return Err(format!("Couldn't list snapshot {}", snapshot.id).into());
+        }
+
+        Ok(Listing {
+            id: snapshot.id.clone(),
+            entries: Listing::parse(&ls.stdout)?,
+        })
+    }
+
+    // One JSON object per line, the snapshot itself followed by all of its nodes
+    fn parse(json: &[u8]) -> Result<HashMap<PathBuf, Entry>, Box<dyn Error>> {
+        let mut entries = HashMap::new();
+        for line in json.split(|&byte| byte == b'\n') {
+            if line.is_empty() {
+                continue;
+            }
+            let node: Value = serde_json::from_slice(line)?;
+            // Older restic versions only set struct_type, newer ones message_type
+            let node_type = node["message_type"]
+                .as_str()
+                .or(node["struct_type"].as_str());
+            if node_type != Some("node") {
+                continue;
+            }
+            let path = node["path"].as_str().ok_or("Invalid node path")?;
+            let entry = parse_node(&node).ok_or("Invalid node")?;
+            // Listed paths are absolute, contents are looked up relative to the snapshot root
+            let path = Path::new(path).strip_prefix("/").unwrap_or(Path::new(path));
+            entries.insert(path.to_owned(), entry);
+        }
+        Ok(entries)
+    }
+}
+
+impl Contents for Listing {
+    fn metadata(&self, path: &Path) -> io::Result<Option<Entry>> {
+        Ok(self.entries.get(path).cloned())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        let mut child = Command::new("restic")
+            .arg("dump")
+            .arg(&self.id)
+            .arg(Path::new("/").join(path))
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().ok_or(io::ErrorKind::BrokenPipe)?;
+        Ok(Box::new(Dump { child, stdout }))
+    }
+
+    fn records_xattrs(&self) -> bool {
+        false
+    }
+
+    fn entries(&self) -> io::Result<Vec<(PathBuf, Entry)>> {
+        Ok(self
+            .entries
+            .iter()
+            .map(|(path, entry)| (path.clone(), entry.clone()))
+            .collect())
+    }
+}
+
+/// Output of `restic dump`, a failed dump is an error instead of a truncated file.
+struct Dump {
+    child: Child,
+    stdout: ChildStdout,
+}
+
+impl Read for Dump {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.stdout.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!("restic dump failed: {}", status)));
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl Drop for Dump {
+    fn drop(&mut self) {
+        // Don't leave a zombie behind if the output wasn't read completely
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_snapshots() -> Result<(), Box<dyn Error>> {
+        let json = br#"[{
+            "time": "2024-04-01T12:00:00.123456789+02:00",
+            "tree": "5b9e8a2f",
+            "paths": ["/home/user/dev/bacify"],
+            "hostname": "laptop",
+            "tags": ["daily"],
+            "excludes": ["*.tmp"],
+            "id": "6a1c3f07d1e5",
+            "short_id": "6a1c3f07"
+        }]"#;
+
+        let snapshots = Restic::parse_snapshots(json)?;
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].id, "6a1c3f07d1e5");
+        assert_eq!(
+            snapshots[0].time,
+            DateTime::parse_from_rfc3339("2024-04-01T10:00:00.123456789Z")?
+        );
+        assert_eq!(snapshots[0].paths, [PathBuf::from("/home/user/dev/bacify")]);
+        assert_eq!(snapshots[0].hostname, "laptop");
+        assert_eq!(snapshots[0].tags, ["daily"]);
+        assert_eq!(snapshots[0].excludes, ["--exclude", "*.tmp"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_listing() -> Result<(), Box<dyn Error>> {
+        let json = br#"{"time":"2024-04-01T12:00:00+02:00","paths":["/home/user"],"id":"6a1c3f07","short_id":"6a1c3f07","struct_type":"snapshot"}
+{"name":"user","type":"dir","path":"/home/user","uid":1000,"gid":1000,"mode":2147484141,"mtime":"2024-03-31T09:00:00.5+02:00","struct_type":"node"}
+{"name":"file","type":"file","path":"/home/user/file","uid":1000,"gid":1000,"size":3,"mode":420,"mtime":"2024-03-31T10:00:00.123456789+02:00","inode":42,"struct_type":"node"}
+{"name":"null","type":"chardev","path":"/home/user/null","device":259,"mode":69206454,"mtime":"2024-03-31T10:00:00+02:00","struct_type":"node"}
+{"name":"link","type":"symlink","path":"/home/user/link","linktarget":"file","uid":1000,"gid":1000,"mode":134218239,"mtime":"2024-03-31T10:00:00+02:00","struct_type":"node"}
+"#;
+
+        let entries = Listing::parse(json)?;
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[Path::new("home/user")].kind, Kind::Dir);
+        let file = &entries[Path::new("home/user/file")];
+        assert!(file.is_file());
+        assert_eq!(file.size, 3);
+        assert_eq!(file.attributes.mode, Some(0o644));
+        assert_eq!(file.attributes.uid, Some(1000));
+        assert_eq!(file.attributes.user, None);
+        assert_eq!(file.inode, Some((0, 42)));
+        let null = &entries[Path::new("home/user/null")];
+        assert_eq!(null.kind, Kind::CharDevice);
+        assert_eq!(null.rdev, Some(259));
+        let link = &entries[Path::new("home/user/link")];
+        assert_eq!(link.kind, Kind::Symlink);
+        assert_eq!(link.target, Some(PathBuf::from("file")));
+        assert_eq!(
+            file.modified,
+            SystemTime::from(DateTime::parse_from_rfc3339(
+                "2024-03-31T08:00:00.123456789Z"
+            )?)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_snapshots_invalid_time() {
+        let json = br#"[{"time": "yesterday", "id": "6a1c3f07", "paths": ["/"]}]"#;
+        assert!(Restic::parse_snapshots(json).is_err());
+    }
+}
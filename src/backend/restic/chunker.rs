@@ -0,0 +1,161 @@
+use sha2::{Digest, Sha256};
+use std::io::{self, Read};
+
+// Parameters of restic's content defined chunker, see https://github.com/restic/chunker
+const WINDOW_SIZE: usize = 64;
+const MIN_SIZE: usize = 512 * 1024;
+const MAX_SIZE: usize = 8 * 1024 * 1024;
+const SPLIT_MASK: u64 = (1 << 20) - 1;
+
+// Polynomials over GF(2) are represented as bit vectors
+fn degree(polynomial: u64) -> i32 {
+    63 - polynomial.leading_zeros() as i32
+}
+
+fn modulo(mut x: u64, polynomial: u64) -> u64 {
+    while degree(x) >= degree(polynomial) {
+        x ^= polynomial << (degree(x) - degree(polynomial));
+    }
+    x
+}
+
+/// Splits data into the same chunks as restic, using a Rabin fingerprint over a sliding window
+/// to find the chunk boundaries. The polynomial is chosen randomly for every repository.
+pub struct Chunker {
+    shift: u32,
+    out_table: [u64; 256],
+    mod_table: [u64; 256],
+}
+
+impl Chunker {
+    pub fn new(polynomial: u64) -> Chunker {
+        let k = degree(polynomial);
+        let mut out_table = [0; 256];
+        let mut mod_table = [0; 256];
+        for b in 0..256 {
+            // Hash of b followed by WINDOW_SIZE - 1 zero bytes, to slide b out of the window
+            let mut hash = modulo(b, polynomial);
+            for _ in 0..WINDOW_SIZE - 1 {
+                hash = modulo(hash << 8, polynomial);
+            }
+            out_table[b as usize] = hash;
+
+            // Reduces the top byte and clears it in a single XOR
+            mod_table[b as usize] = modulo(b << k, polynomial) | (b << k);
+        }
+
+        Chunker {
+            shift: (k - 8) as u32,
+            out_table,
+            mod_table,
+        }
+    }
+
+    /// The SHA-256 hashes of the chunks of `reader`, i.e. the ids of the data blobs restic
+    /// stores for it.
+    pub fn blob_ids(&self, reader: &mut dyn Read) -> io::Result<Vec<[u8; 32]>> {
+        let mut ids = Vec::new();
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; MIN_SIZE];
+
+        let mut window = [0; WINDOW_SIZE];
+        let mut position = 0;
+        let mut digest = 0;
+        let mut count = 0;
+
+        loop {
+            let read = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            let mut start = 0;
+            for (i, &b) in buf[..read].iter().enumerate() {
+                count += 1;
+                // The boundary is never within the first MIN_SIZE bytes, so only the last
+                // window of those needs to be hashed
+                if count <= MIN_SIZE - WINDOW_SIZE {
+                    continue;
+                }
+
+                digest ^= self.out_table[window[position] as usize];
+                window[position] = b;
+                position = (position + 1) % WINDOW_SIZE;
+                let index = (digest >> self.shift) as u8;
+                digest = ((digest << 8) | b as u64) ^ self.mod_table[index as usize];
+
+                if count >= MIN_SIZE && (digest & SPLIT_MASK == 0 || count >= MAX_SIZE) {
+                    hasher.update(&buf[start..=i]);
+                    ids.push(hasher.finalize_reset().into());
+                    start = i + 1;
+
+                    window = [0; WINDOW_SIZE];
+                    position = 0;
+                    digest = 0;
+                    count = 0;
+                }
+            }
+            hasher.update(&buf[start..read]);
+        }
+
+        if count > 0 {
+            ids.push(hasher.finalize().into());
+        }
+        Ok(ids)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The polynomial used in the tests of restic's chunker
+    const POLYNOMIAL: u64 = 0x3DA3358B4DC173;
+
+    fn random_data(len: usize) -> Vec<u8> {
+        // xorshift, good enough to get some chunk boundaries
+        let mut state: u64 = 23;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_small_file_is_one_blob() -> io::Result<()> {
+        let chunker = Chunker::new(POLYNOMIAL);
+        let data = random_data(1000);
+
+        let ids = chunker.blob_ids(&mut data.as_slice())?;
+        assert_eq!(ids, [<[u8; 32]>::from(Sha256::digest(&data))]);
+        assert!(chunker.blob_ids(&mut io::empty())?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunk_boundaries() -> io::Result<()> {
+        let chunker = Chunker::new(POLYNOMIAL);
+        let data = random_data(12 * 1024 * 1024);
+
+        let ids = chunker.blob_ids(&mut data.as_slice())?;
+        assert!(ids.len() > 3);
+
+        // Boundaries only depend on the data, not on how it is read
+        let mut reader = io::BufReader::with_capacity(4096, data.as_slice());
+        assert_eq!(chunker.blob_ids(&mut reader)?, ids);
+
+        // Changing the start of the data shifts the first boundary at most, later chunks stay
+        let mut changed = data.clone();
+        changed[0] ^= 1;
+        let changed_ids = chunker.blob_ids(&mut changed.as_slice())?;
+        assert_ne!(changed_ids[0], ids[0]);
+        assert_eq!(changed_ids[1..], ids[1..]);
+        Ok(())
+    }
+}
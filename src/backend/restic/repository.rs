@@ -0,0 +1,550 @@
+use super::chunker::Chunker;
+use super::{parse_node, parse_snapshot};
+use crate::backend::{self, Backend, Contents, Entry, Snapshot};
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockEncrypt, KeyInit, KeyIvInit, StreamCipher};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use log::info;
+use poly1305::Poly1305;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+type Aes256Ctr = ctr::Ctr128BE<aes::Aes256>;
+
+/// Blobs, packs and files are identified by the SHA-256 of their contents.
+type Id = [u8; 32];
+
+fn parse_id(hex: &str) -> Option<Id> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut id = [0; 32];
+    for (i, byte) in id.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(id)
+}
+
+fn to_hex(id: &Id) -> String {
+    id.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// AES-256-CTR encryption key and Poly1305-AES MAC key, see
+/// https://restic.readthedocs.io/en/stable/100_references.html#keys-encryption-and-mac
+#[derive(Clone)]
+struct Key {
+    encrypt: [u8; 32],
+    mac_k: [u8; 16],
+    mac_r: [u8; 16],
+}
+
+impl Key {
+    // The master key is stored in a key file, encrypted with a key derived from the password.
+    // Returns None if the password doesn't fit.
+    fn open(key_file: &[u8], password: &str) -> Result<Option<Key>, Box<dyn Error>> {
+        let key_file: Value = serde_json::from_slice(key_file)?;
+        if key_file["kdf"] != "scrypt" {
+            return Err(format!("Unsupported key derivation function {}", key_file["kdf"]).into());
+        }
+        let n = key_file["N"].as_u64().ok_or("Invalid scrypt parameter N")?;
+        let r = key_file["r"].as_u64().ok_or("Invalid scrypt parameter r")?;
+        let p = key_file["p"].as_u64().ok_or("Invalid scrypt parameter p")?;
+        let salt = BASE64_STANDARD.decode(key_file["salt"].as_str().ok_or("Invalid salt")?)?;
+        let data = BASE64_STANDARD.decode(key_file["data"].as_str().ok_or("Invalid key data")?)?;
+
+        let params = scrypt::Params::new(n.trailing_zeros() as u8, r as u32, p as u32, 64)
+            .map_err(|_| "Invalid scrypt parameters")?;
+        let mut derived = [0; 64];
+        scrypt::scrypt(password.as_bytes(), &salt, &params, &mut derived)
+            .map_err(|_| "Key derivation failed")?;
+        let user_key = Key::from_bytes(&derived);
+
+        let master = match user_key.decrypt(&data) {
+            Ok(master) => master,
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let master: Value = serde_json::from_slice(&master)?;
+        let decode = |key: &Value| BASE64_STANDARD.decode(key.as_str()?).ok();
+        Ok(Some(Key {
+            encrypt: decode(&master["encrypt"])
+                .and_then(|key| key.try_into().ok())
+                .ok_or("Invalid encryption key")?,
+            mac_k: decode(&master["mac"]["k"])
+                .and_then(|key| key.try_into().ok())
+                .ok_or("Invalid MAC key")?,
+            mac_r: decode(&master["mac"]["r"])
+                .and_then(|key| key.try_into().ok())
+                .ok_or("Invalid MAC key")?,
+        }))
+    }
+
+    fn from_bytes(bytes: &[u8; 64]) -> Key {
+        let mut key = Key {
+            encrypt: [0; 32],
+            mac_k: [0; 16],
+            mac_r: [0; 16],
+        };
+        key.encrypt.copy_from_slice(&bytes[..32]);
+        key.mac_k.copy_from_slice(&bytes[32..48]);
+        key.mac_r.copy_from_slice(&bytes[48..]);
+        key
+    }
+
+    fn mac(&self, nonce: &[u8], data: &[u8]) -> [u8; 16] {
+        // Poly1305-AES uses the nonce encrypted with AES-128 as second half of the Poly1305 key
+        let mut s = GenericArray::clone_from_slice(nonce);
+        aes::Aes128::new(GenericArray::from_slice(&self.mac_k)).encrypt_block(&mut s);
+        let mut poly1305_key = [0; 32];
+        poly1305_key[..16].copy_from_slice(&self.mac_r);
+        poly1305_key[16..].copy_from_slice(&s);
+        Poly1305::new(GenericArray::from_slice(&poly1305_key))
+            .compute_unpadded(data)
+            .into()
+    }
+
+    // Ciphertexts are the nonce, followed by the encrypted data and the MAC
+    fn decrypt(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        if data.len() < 32 {
+            return Err(invalid_data("Ciphertext is too short".into()));
+        }
+        let (nonce, rest) = data.split_at(16);
+        let (ciphertext, mac) = rest.split_at(rest.len() - 16);
+        if self.mac(nonce, ciphertext) != mac {
+            return Err(invalid_data("MAC verification failed".into()));
+        }
+
+        let mut plaintext = ciphertext.to_vec();
+        Aes256Ctr::new(
+            GenericArray::from_slice(&self.encrypt),
+            GenericArray::from_slice(nonce),
+        )
+        .apply_keystream(&mut plaintext);
+        Ok(plaintext)
+    }
+}
+
+#[derive(Clone)]
+struct Location {
+    pack: Id,
+    offset: u64,
+    length: u64,
+    compressed: bool,
+}
+
+/// A local restic repository, read without the restic binary.
+#[derive(Clone)]
+pub struct Repository {
+    path: PathBuf,
+    key: Key,
+    index: HashMap<Id, Location>,
+    chunk_hashes: bool,
+}
+
+impl Repository {
+    pub fn open(path: PathBuf, password: &str) -> Result<Repository, Box<dyn Error>> {
+        let keys = fs::read_dir(path.join("keys"))
+            .map_err(|e| format!("Couldn't open repository {:?}: {}", path, e))?;
+        for key_file in keys {
+            if let Some(key) = Key::open(&fs::read(key_file?.path())?, password)? {
+                return Ok(Repository {
+                    path,
+                    key,
+                    index: HashMap::new(),
+                    chunk_hashes: false,
+                });
+            }
+        }
+        Err("Wrong password or no key found".into())
+    }
+
+    /// Open the repository given by RESTIC_REPOSITORY with RESTIC_PASSWORD or
+    /// RESTIC_PASSWORD_FILE, like restic does.
+    pub fn from_env() -> Result<Repository, Box<dyn Error>> {
+        let repository = std::env::var("RESTIC_REPOSITORY")
+            .map_err(|_| "Couldn't find the repository. Did you set RESTIC_REPOSITORY?")?;
+        let password = match std::env::var("RESTIC_PASSWORD") {
+            Ok(password) => password,
+            Err(_) => {
+                let password_file = std::env::var("RESTIC_PASSWORD_FILE").map_err(|_| {
+                    "Couldn't find the password. Did you set RESTIC_PASSWORD or RESTIC_PASSWORD_FILE?"
+                })?;
+                let password = fs::read_to_string(password_file)?;
+                password.trim_end_matches(['\r', '\n']).to_owned()
+            }
+        };
+
+        let path = repository.strip_prefix("local:").unwrap_or(&repository);
+        if !Path::new(path).is_dir() {
+            return Err(
+                format!("Only local repositories are supported, not {}", repository).into(),
+            );
+        }
+        Repository::open(PathBuf::from(path), &password)
+    }
+
+    /// Compare file contents by chunking the local file like restic and comparing the blob ids
+    /// with the ones in the snapshot, instead of reading the blobs from the repository.
+    pub fn chunk_hashes(mut self, chunk_hashes: bool) -> Repository {
+        self.chunk_hashes = chunk_hashes;
+        self
+    }
+
+    fn chunker(&self) -> Result<Chunker, Box<dyn Error>> {
+        let config: Value = serde_json::from_slice(&self.read_file(&self.path.join("config"))?)?;
+        let polynomial = config["chunker_polynomial"]
+            .as_str()
+            .and_then(|polynomial| u64::from_str_radix(polynomial, 16).ok())
+            .ok_or("Invalid chunker polynomial")?;
+        Ok(Chunker::new(polynomial))
+    }
+
+    fn list(&self, kind: &str) -> io::Result<Vec<String>> {
+        fs::read_dir(self.path.join(kind))?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect()
+    }
+
+    // Files other than packs, i.e. snapshots and indexes
+    fn read(&self, kind: &str, name: &str) -> io::Result<Vec<u8>> {
+        self.read_file(&self.path.join(kind).join(name))
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let data = self.key.decrypt(&fs::read(path)?)?;
+        // Repository version 2 compresses files, marked by a version byte in front of the
+        // compressed JSON
+        match data.first() {
+            Some(2) => zstd::decode_all(&data[1..]),
+            _ => Ok(data),
+        }
+    }
+
+    fn read_json(&self, kind: &str, name: &str) -> Result<Value, Box<dyn Error>> {
+        Ok(serde_json::from_slice(&self.read(kind, name)?)?)
+    }
+
+    fn load_index(&mut self) -> Result<(), Box<dyn Error>> {
+        info!("Loading index...");
+        for name in self.list("index")? {
+            let index = self.read_json("index", &name)?;
+            for pack in index["packs"].as_array().ok_or("Invalid index")? {
+                let pack_id = pack["id"]
+                    .as_str()
+                    .and_then(parse_id)
+                    .ok_or("Invalid pack id")?;
+                for blob in pack["blobs"].as_array().ok_or("Invalid index")? {
+                    let id = blob["id"]
+                        .as_str()
+                        .and_then(parse_id)
+                        .ok_or("Invalid blob id")?;
+                    let location = Location {
+                        pack: pack_id,
+                        offset: blob["offset"].as_u64().ok_or("Invalid blob offset")?,
+                        length: blob["length"].as_u64().ok_or("Invalid blob length")?,
+                        compressed: blob["uncompressed_length"].is_u64(),
+                    };
+                    self.index.insert(id, location);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn blob(&self, id: &Id) -> io::Result<Vec<u8>> {
+        let location = self.index.get(id).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Blob {} is not in the index", to_hex(id)),
+            )
+        })?;
+        let pack_id = to_hex(&location.pack);
+        let mut pack = fs::File::open(self.path.join("data").join(&pack_id[..2]).join(&pack_id))?;
+        pack.seek(SeekFrom::Start(location.offset))?;
+        let mut data = vec![0; location.length as usize];
+        pack.read_exact(&mut data)?;
+
+        let data = self.key.decrypt(&data)?;
+        let data = if location.compressed {
+            zstd::decode_all(data.as_slice())?
+        } else {
+            data
+        };
+        if backend::sha256(&mut data.as_slice())? != *id {
+            return Err(invalid_data(format!("Blob {} is corrupt", to_hex(id))));
+        }
+        Ok(data)
+    }
+}
+
+impl Backend for Repository {
+    fn snapshots(&self) -> Result<Vec<Snapshot>, Box<dyn Error>> {
+        self.list("snapshots")?
+            .iter()
+            .map(|id| parse_snapshot(&self.read_json("snapshots", id)?, id))
+            .collect()
+    }
+
+    fn open(&self, snapshot: &Snapshot) -> Result<Box<dyn Contents>, Box<dyn Error>> {
+        let mut repository = self.clone();
+        repository.load_index()?;
+        Ok(Box::new(Tree::load(repository, snapshot)?))
+    }
+}
+
+/// All nodes of a snapshot with the blobs of their contents.
+struct Tree {
+    repository: Repository,
+    nodes: HashMap<PathBuf, (Entry, Vec<Id>)>,
+    chunker: Option<Chunker>,
+}
+
+impl Tree {
+    fn load(repository: Repository, snapshot: &Snapshot) -> Result<Tree, Box<dyn Error>> {
+        info!("Loading snapshot {}...", snapshot.id);
+        let root = repository.read_json("snapshots", &snapshot.id)?["tree"]
+            .as_str()
+            .and_then(parse_id)
+            .ok_or("Invalid snapshot tree")?;
+
+        let mut nodes = HashMap::new();
+        let mut trees = vec![(PathBuf::new(), root)];
+        while let Some((dir, id)) = trees.pop() {
+            let tree: Value = serde_json::from_slice(&repository.blob(&id)?)?;
+            for node in tree["nodes"].as_array().ok_or("Invalid tree")? {
+                let path = dir.join(node["name"].as_str().ok_or("Invalid node name")?);
+                let mut entry = parse_node(node).ok_or("Invalid node")?;
+                // Nodes without extended attributes leave them out
+                entry.attributes.xattrs.get_or_insert_default();
+                if let Some(subtree) = node["subtree"].as_str() {
+                    let subtree = parse_id(subtree).ok_or("Invalid subtree id")?;
+                    trees.push((path.clone(), subtree));
+                }
+                let content = match node["content"].as_array() {
+                    Some(content) => content
+                        .iter()
+                        .map(|id| id.as_str().and_then(parse_id))
+                        .collect::<Option<Vec<Id>>>()
+                        .ok_or("Invalid content id")?,
+                    None => Vec::new(),
+                };
+                nodes.insert(path, (entry, content));
+            }
+        }
+
+        let chunker = if repository.chunk_hashes {
+            Some(repository.chunker()?)
+        } else {
+            None
+        };
+        Ok(Tree {
+            repository,
+            nodes,
+            chunker,
+        })
+    }
+}
+
+impl Contents for Tree {
+    fn metadata(&self, path: &Path) -> io::Result<Option<Entry>> {
+        Ok(self.nodes.get(path).map(|(entry, _)| entry.clone()))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        let (_, content) = self
+            .nodes
+            .get(path)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        Ok(Box::new(Blobs {
+            repository: &self.repository,
+            ids: content.iter(),
+            current: io::Cursor::new(Vec::new()),
+        }))
+    }
+
+    fn same_content(&self, path: &Path, file: &Path) -> io::Result<bool> {
+        let Some(chunker) = &self.chunker else {
+            return Ok(backend::sha256(&mut fs::File::open(file)?)? == self.sha256(path)?);
+        };
+        let (_, content) = self
+            .nodes
+            .get(path)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        Ok(chunker.blob_ids(&mut fs::File::open(file)?)? == *content)
+    }
+
+    fn entries(&self) -> io::Result<Vec<(PathBuf, Entry)>> {
+        Ok(self
+            .nodes
+            .iter()
+            .map(|(path, (entry, _))| (path.clone(), entry.clone()))
+            .collect())
+    }
+}
+
+/// Reads the blobs of a file one after the other.
+struct Blobs<'a> {
+    repository: &'a Repository,
+    ids: std::slice::Iter<'a, Id>,
+    current: io::Cursor<Vec<u8>>,
+}
+
+impl Read for Blobs<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.ids.next() {
+                Some(id) => self.current = io::Cursor::new(self.repository.blob(id)?),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Key {
+        fn encrypt(&self, nonce: [u8; 16], plaintext: &[u8]) -> Vec<u8> {
+            let mut ciphertext = plaintext.to_vec();
+            Aes256Ctr::new(
+                GenericArray::from_slice(&self.encrypt),
+                GenericArray::from_slice(&nonce),
+            )
+            .apply_keystream(&mut ciphertext);
+            let mac = self.mac(&nonce, &ciphertext);
+            [nonce.as_slice(), &ciphertext, &mac].concat()
+        }
+    }
+
+    fn write_repository(path: &Path, password: &str) -> Result<(), Box<dyn Error>> {
+        for dir in ["keys", "snapshots", "index", "data/00"] {
+            fs::create_dir_all(path.join(dir))?;
+        }
+
+        let master = Key::from_bytes(&[7; 64]);
+        let salt = [1; 16];
+        let params = scrypt::Params::new(10, 8, 1, 64).unwrap();
+        let mut derived = [0; 64];
+        scrypt::scrypt(password.as_bytes(), &salt, &params, &mut derived).unwrap();
+        let master_json = serde_json::json!({
+            "mac": {"k": BASE64_STANDARD.encode(master.mac_k), "r": BASE64_STANDARD.encode(master.mac_r)},
+            "encrypt": BASE64_STANDARD.encode(master.encrypt),
+        });
+        let key_file = serde_json::json!({
+            "kdf": "scrypt", "N": 1024, "r": 8, "p": 1,
+            "salt": BASE64_STANDARD.encode(salt),
+            "data": BASE64_STANDARD.encode(Key::from_bytes(&derived).encrypt([2; 16], master_json.to_string().as_bytes())),
+        });
+        fs::write(path.join("keys/key"), key_file.to_string())?;
+
+        let config = serde_json::json!({"version": 1, "id": to_hex(&[3; 32]), "chunker_polynomial": "3da3358b4dc173"});
+        fs::write(
+            path.join("config"),
+            master.encrypt([8; 16], config.to_string().as_bytes()),
+        )?;
+
+        let data = b"foo";
+        let data_id = backend::sha256(&mut data.as_slice())?;
+        let tree = serde_json::json!({"nodes": [{
+            "name": "file", "type": "file", "mode": 420, "size": 3,
+            "mtime": "2024-03-31T10:00:00.123456789+02:00",
+            "content": [to_hex(&data_id)],
+        }]});
+        let tree = tree.to_string().into_bytes();
+        let tree_id = backend::sha256(&mut tree.as_slice())?;
+        let data_blob = master.encrypt([3; 16], data);
+        let tree_blob = master.encrypt([4; 16], &tree);
+        let pack_id = to_hex(&[0; 32]);
+        fs::write(
+            path.join("data/00").join(&pack_id),
+            [data_blob.as_slice(), &tree_blob].concat(),
+        )?;
+
+        let index = serde_json::json!({"packs": [{"id": pack_id, "blobs": [
+            {"id": to_hex(&data_id), "type": "data", "offset": 0, "length": data_blob.len()},
+            {"id": to_hex(&tree_id), "type": "tree", "offset": data_blob.len(), "length": tree_blob.len()},
+        ]}]});
+        fs::write(
+            path.join("index").join(to_hex(&[1; 32])),
+            master.encrypt([5; 16], index.to_string().as_bytes()),
+        )?;
+
+        let snapshot = serde_json::json!({
+            "time": "2024-04-01T12:00:00+02:00",
+            "tree": to_hex(&tree_id),
+            "paths": ["/home/user"],
+        });
+        fs::write(
+            path.join("snapshots").join(to_hex(&[2; 32])),
+            master.encrypt([6; 16], snapshot.to_string().as_bytes()),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_repository() -> Result<(), Box<dyn Error>> {
+        let temp_dir = tempfile::TempDir::with_prefix("bacify-test-")?;
+        write_repository(temp_dir.path(), "foo")?;
+
+        let repository = Repository::open(temp_dir.path().to_owned(), "foo")?;
+        let snapshots = repository.snapshots()?;
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].paths, [PathBuf::from("/home/user")]);
+
+        let contents = repository.open(&snapshots[0])?;
+        let file = contents.metadata(Path::new("file"))?.unwrap();
+        assert!(file.is_file());
+        assert_eq!(file.size, 3);
+        assert_eq!(
+            contents.sha256(Path::new("file"))?,
+            backend::sha256(&mut "foo".as_bytes())?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunk_hashes() -> Result<(), Box<dyn Error>> {
+        let temp_dir = tempfile::TempDir::with_prefix("bacify-test-")?;
+        write_repository(temp_dir.path(), "foo")?;
+        fs::write(temp_dir.path().join("same"), "foo")?;
+        fs::write(temp_dir.path().join("different"), "bar")?;
+
+        let repository = Repository::open(temp_dir.path().to_owned(), "foo")?.chunk_hashes(true);
+        let contents = repository.open(&repository.snapshots()?[0])?;
+        let file = Path::new("file");
+        assert!(contents.same_content(file, &temp_dir.path().join("same"))?);
+        assert!(!contents.same_content(file, &temp_dir.path().join("different"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrong_password() -> Result<(), Box<dyn Error>> {
+        let temp_dir = tempfile::TempDir::with_prefix("bacify-test-")?;
+        write_repository(temp_dir.path(), "foo")?;
+
+        assert!(Repository::open(temp_dir.path().to_owned(), "bar").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_decrypt_tampered() {
+        let key = Key::from_bytes(&[7; 64]);
+        let mut ciphertext = key.encrypt([1; 16], b"foo");
+        assert_eq!(key.decrypt(&ciphertext).unwrap(), b"foo");
+        ciphertext[16] ^= 1;
+        assert!(key.decrypt(&ciphertext).is_err());
+    }
+}
@@ -0,0 +1,630 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+// A single path component of a pattern
+#[derive(Clone, Debug)]
+enum Part {
+    Literal(String),
+    Glob(Vec<char>),
+    // `**`, any number of path components
+    Any,
+}
+
+impl Part {
+    fn new(part: &str) -> Part {
+        if part == "**" {
+            Part::Any
+        } else if part.contains(['\\', '[', ']', '*', '?']) {
+            Part::Glob(part.chars().collect())
+        } else {
+            Part::Literal(part.to_owned())
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Part::Literal(literal) => literal == name,
+            Part::Glob(glob) => matches_glob(glob, &name.chars().collect::<Vec<char>>()),
+            Part::Any => true,
+        }
+    }
+}
+
+// Shell pattern matching of a single path component, like Go's filepath.Match that restic uses.
+// Malformed patterns don't match anything.
+fn matches_glob(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|i| matches_glob(rest, &name[i..])),
+        Some(('?', rest)) => !name.is_empty() && matches_glob(rest, &name[1..]),
+        Some(('[', rest)) => match name.split_first() {
+            Some((&c, name)) => match matches_class(rest, c) {
+                Some((true, rest)) => matches_glob(rest, name),
+                _ => false,
+            },
+            None => false,
+        },
+        Some(('\\', rest)) => match rest.split_first() {
+            Some((p, rest)) => name.first() == Some(p) && matches_glob(rest, &name[1..]),
+            None => false,
+        },
+        Some((p, rest)) => name.first() == Some(p) && matches_glob(rest, &name[1..]),
+    }
+}
+
+// Matches c against a character class like `[^a-z]`, after the opening bracket. Returns whether
+// it matched and the rest of the pattern, or None if the class is malformed.
+fn matches_class(pattern: &[char], c: char) -> Option<(bool, &[char])> {
+    let (negated, mut pattern) = match pattern.split_first() {
+        Some(('^', rest)) => (true, rest),
+        _ => (false, pattern),
+    };
+
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        if let Some((']', rest)) = pattern.split_first() {
+            if !first {
+                return Some((matched != negated, rest));
+            }
+        }
+        first = false;
+
+        let (low, rest) = class_char(pattern)?;
+        let (high, rest) = match rest.split_first() {
+            Some(('-', rest)) => class_char(rest)?,
+            _ => (low, rest),
+        };
+        matched |= low <= c && c <= high;
+        pattern = rest;
+    }
+}
+
+fn class_char(pattern: &[char]) -> Option<(char, &[char])> {
+    match pattern.split_first()? {
+        ('\\', rest) => rest.split_first().map(|(&c, rest)| (c, rest)),
+        ('-' | ']', _) => None,
+        (&c, rest) => Some((c, rest)),
+    }
+}
+
+// The components of a path, with "/" as first component of absolute paths
+fn split(path: &Path) -> Vec<String> {
+    let mut parts: Vec<String> = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::RootDir => parts.push("/".to_owned()),
+            Component::Normal(name) => parts.push(name.to_string_lossy().into_owned()),
+            Component::ParentDir => {
+                parts.pop();
+            }
+            Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    parts
+}
+
+/// An exclude pattern with the syntax of restic.
+#[derive(Clone, Debug)]
+pub struct Pattern {
+    parts: Vec<Part>,
+    negated: bool,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Pattern {
+        let (negated, pattern) = match pattern.strip_prefix('!') {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        Pattern {
+            parts: split(Path::new(pattern))
+                .iter()
+                .map(|part| Part::new(part))
+                .collect(),
+            negated,
+        }
+    }
+
+    fn rooted(&self) -> bool {
+        matches!(self.parts.first(), Some(Part::Literal(root)) if root == "/")
+    }
+
+    // The pattern matches if it matches consecutive components of the path, starting anywhere
+    // unless the pattern is absolute. It doesn't need to match up to the end, so a pattern
+    // matching a directory matches everything in it as well.
+    fn matches(&self, path: &[String]) -> bool {
+        if self.parts.is_empty() {
+            return false;
+        }
+
+        let offsets = if self.rooted() {
+            0..1
+        } else if path.first().is_some_and(|root| root == "/") {
+            1..path.len()
+        } else {
+            0..path.len()
+        };
+        offsets
+            .into_iter()
+            .any(|offset| matches_prefix(&self.parts, &path[offset..]))
+    }
+
+    // The name of a pattern like `node_modules`, that matches a single component without wildcards
+    fn name(&self) -> Option<&str> {
+        match self.parts.as_slice() {
+            [Part::Literal(name)] if name != "/" => Some(name),
+            _ => None,
+        }
+    }
+
+    // The path of a pattern like `/home/user/.cache`, that is absolute and has no wildcards
+    fn literal_path(&self) -> Option<Vec<String>> {
+        if !self.rooted() {
+            return None;
+        }
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(name) => Some(name.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+// `**` stands for any number of components, also none
+fn matches_prefix(parts: &[Part], path: &[String]) -> bool {
+    match parts.split_first() {
+        None => true,
+        Some((Part::Any, rest)) => (0..=path.len()).any(|i| matches_prefix(rest, &path[i..])),
+        Some((part, rest)) => {
+            !path.is_empty() && part.matches(&path[0]) && matches_prefix(rest, &path[1..])
+        }
+    }
+}
+
+// The last matching pattern decides whether a path is excluded
+fn list(patterns: &[Pattern], path: &[String]) -> bool {
+    let mut excluded = false;
+    for pattern in patterns {
+        // Only a negated pattern can change the result once a path is excluded
+        if excluded != pattern.negated {
+            continue;
+        }
+        if pattern.matches(path) {
+            excluded = !pattern.negated;
+        }
+    }
+    excluded
+}
+
+// Without negated patterns the order doesn't matter, so the patterns without wildcards can be
+// looked up instead of trying them one by one.
+#[derive(Clone, Debug, Default)]
+struct PatternSet {
+    patterns: Vec<Pattern>,
+    negated: bool,
+    names: HashSet<String>,
+    paths: HashSet<Vec<String>>,
+    globs: Vec<Pattern>,
+}
+
+impl PatternSet {
+    fn add(&mut self, pattern: Pattern) {
+        if pattern.negated {
+            self.negated = true;
+        } else if let Some(name) = pattern.name() {
+            self.names.insert(name.to_owned());
+        } else if let Some(path) = pattern.literal_path() {
+            self.paths.insert(path);
+        } else {
+            self.globs.push(pattern.clone());
+        }
+        self.patterns.push(pattern);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    fn matches(&self, path: &[String]) -> bool {
+        if self.negated {
+            return list(&self.patterns, path);
+        }
+
+        let names = match path.first() {
+            Some(root) if root == "/" => &path[1..],
+            _ => path,
+        };
+        names.iter().any(|name| self.names.contains(name))
+            || (1..=path.len()).any(|len| self.paths.contains(&path[..len]))
+            || self.globs.iter().any(|pattern| pattern.matches(path))
+    }
+}
+
+/// The exclude patterns, a path is excluded if either the case sensitive or the case insensitive
+/// patterns exclude it. Like with restic, a negated pattern only affects patterns of its own kind.
+#[derive(Clone, Debug, Default)]
+pub struct Excludes {
+    patterns: PatternSet,
+    insensitive: PatternSet,
+}
+
+impl Excludes {
+    pub fn add(&mut self, pattern: &str) {
+        self.patterns.add(Pattern::new(pattern));
+    }
+
+    pub fn add_insensitive(&mut self, pattern: &str) {
+        self.insensitive.add(Pattern::new(&pattern.to_lowercase()));
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        if !self.patterns.is_empty() && self.patterns.matches(&split(path)) {
+            return true;
+        }
+        if !self.insensitive.is_empty() {
+            let path = split(Path::new(&path.to_string_lossy().to_lowercase()));
+            return self.insensitive.matches(&path);
+        }
+        false
+    }
+}
+
+// Unlike restic patterns, gitignore patterns have to match the whole path
+fn matches_exact(parts: &[Part], path: &[String]) -> bool {
+    match parts.split_first() {
+        None => path.is_empty(),
+        Some((Part::Any, rest)) => (0..=path.len()).any(|i| matches_exact(rest, &path[i..])),
+        Some((part, rest)) => {
+            !path.is_empty() && part.matches(&path[0]) && matches_exact(rest, &path[1..])
+        }
+    }
+}
+
+// A pattern of a gitignore style file
+#[derive(Clone, Debug)]
+struct IgnorePattern {
+    parts: Vec<Part>,
+    negated: bool,
+    // A pattern with a trailing slash only matches directories
+    dir_only: bool,
+    // A pattern with a slash is relative to the ignore file, others match names at any depth
+    anchored: bool,
+}
+
+impl IgnorePattern {
+    fn new(line: &str) -> Option<IgnorePattern> {
+        // Trailing spaces are ignored unless escaped
+        let trimmed = line.trim_end_matches(' ');
+        let line = if trimmed.ends_with('\\') && trimmed.len() < line.len() {
+            &line[..trimmed.len() + 1]
+        } else {
+            trimmed
+        };
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let (dir_only, line) = match line.strip_suffix('/') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let anchored = line.contains('/');
+        let parts = line
+            .split('/')
+            .filter(|part| !part.is_empty())
+            .map(Part::new)
+            .collect::<Vec<Part>>();
+        if parts.is_empty() {
+            return None;
+        }
+
+        Some(IgnorePattern {
+            parts,
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    fn matches(&self, path: &[String], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            matches_exact(&self.parts, path)
+        } else {
+            path.last().is_some_and(|name| self.parts[0].matches(name))
+        }
+    }
+}
+
+/// A `.gitignore` style file, its patterns apply to the directory it is in.
+#[derive(Clone, Debug)]
+pub struct IgnoreFile {
+    dir: PathBuf,
+    patterns: Vec<IgnorePattern>,
+}
+
+impl IgnoreFile {
+    pub const NAME: &'static str = ".bacifyignore";
+
+    /// The ignore file in `dir`, if there is one.
+    pub fn load(dir: &Path) -> io::Result<Option<IgnoreFile>> {
+        match std::fs::read_to_string(dir.join(IgnoreFile::NAME)) {
+            Ok(contents) => Ok(Some(IgnoreFile::parse(dir, &contents))),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn parse(dir: &Path, contents: &str) -> IgnoreFile {
+        IgnoreFile {
+            dir: dir.to_owned(),
+            patterns: contents.lines().filter_map(IgnorePattern::new).collect(),
+        }
+    }
+
+    /// Whether the last matching pattern ignores or re-includes `path`, None if no pattern
+    /// matches. Files in ignored directories can't be re-included, they have to be skipped.
+    pub fn matches(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let path = split(path.strip_prefix(&self.dir).ok()?);
+        self.patterns
+            .iter()
+            .rev()
+            .find(|pattern| pattern.matches(&path, is_dir))
+            .map(|pattern| !pattern.negated)
+    }
+}
+
+/// A file that excludes the contents of the directory it is in, except for itself, like restic's
+/// `--exclude-if-present name[:header]`.
+#[derive(Clone, Debug)]
+pub struct Marker {
+    pub name: String,
+    header: Option<String>,
+}
+
+impl Marker {
+    pub fn new(spec: &str) -> Marker {
+        match spec.split_once(':') {
+            Some((name, header)) => Marker {
+                name: name.to_owned(),
+                header: Some(header.to_owned()),
+            },
+            None => Marker {
+                name: spec.to_owned(),
+                header: None,
+            },
+        }
+    }
+
+    /// A cache directory tag, see https://bford.info/cachedir/
+    pub fn cache_dir() -> Marker {
+        Marker::new("CACHEDIR.TAG:Signature: 8a477f597d28d172789f06886806bc55")
+    }
+
+    /// Whether `dir` contains the marker, with the header at its start if there is one.
+    pub fn present(&self, dir: &Path) -> io::Result<bool> {
+        let path = dir.join(&self.name);
+        let Some(header) = &self.header else {
+            return Ok(path.exists());
+        };
+
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let mut start = Vec::with_capacity(header.len());
+        file.take(header.len() as u64).read_to_end(&mut start)?;
+        Ok(start == header.as_bytes())
+    }
+}
+
+/// Parses a size like restic's `--exclude-larger-than`, e.g. `2048`, `500k` or `1G`.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (number, unit) = match size.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&size[..i], c.to_ascii_lowercase()),
+        _ => (size, 'b'),
+    };
+    let factor: u64 = match unit {
+        'b' => 1,
+        'k' => 1 << 10,
+        'm' => 1 << 20,
+        'g' => 1 << 30,
+        't' => 1 << 40,
+        _ => return Err(format!("Invalid unit in size {:?}", size)),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(factor))
+        .ok_or_else(|| format!("Invalid size {:?}", size))
+}
+
+/// Replaces `$VAR` and `${VAR}` with the value of the environment variable, like restic does for
+/// the lines of exclude files. Unset variables are replaced by nothing.
+pub fn expand_env(line: &str) -> String {
+    let mut expanded = String::new();
+    let mut rest = line;
+    while let Some(position) = rest.find('$') {
+        expanded.push_str(&rest[..position]);
+        rest = &rest[position + 1..];
+
+        let (name, len) = if let Some(braced) = rest.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => {
+                    expanded.push('$');
+                    continue;
+                }
+            }
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            (&rest[..end], end)
+        };
+
+        if len == 0 {
+            expanded.push('$');
+        } else {
+            expanded.push_str(&std::env::var(name).unwrap_or_default());
+        }
+        rest = &rest[len..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn excludes(patterns: &[&str]) -> Excludes {
+        let mut excludes = Excludes::default();
+        for pattern in patterns {
+            excludes.add(pattern);
+        }
+        excludes
+    }
+
+    #[test]
+    fn test_glob() {
+        let excludes = excludes(&["*.tmp", "cache?", "[a-c]x[^0-9]", "\\*"]);
+        assert!(excludes.matches(Path::new("/home/user/file.tmp")));
+        assert!(excludes.matches(Path::new("/home/user/cache1/file")));
+        assert!(excludes.matches(Path::new("/bxy")));
+        assert!(excludes.matches(Path::new("/home/*")));
+        assert!(!excludes.matches(Path::new("/home/user/file.tmp.txt")));
+        assert!(!excludes.matches(Path::new("/home/user/cache")));
+        assert!(!excludes.matches(Path::new("/bx1")));
+        assert!(!excludes.matches(Path::new("/dx")));
+    }
+
+    #[test]
+    fn test_rooted() {
+        let excludes = excludes(&["/home/*/tmp", "user/src"]);
+        assert!(excludes.matches(Path::new("/home/user/tmp/file")));
+        assert!(!excludes.matches(Path::new("/other/home/user/tmp")));
+        assert!(excludes.matches(Path::new("/home/user/src/main.rs")));
+        assert!(excludes.matches(Path::new("/srv/user/src")));
+        assert!(!excludes.matches(Path::new("/home/user/source")));
+    }
+
+    #[test]
+    fn test_double_wildcard() {
+        let excludes = excludes(&["/home/**/target", "foo/**/*.o"]);
+        assert!(excludes.matches(Path::new("/home/target")));
+        assert!(excludes.matches(Path::new("/home/user/dev/bacify/target/debug")));
+        assert!(excludes.matches(Path::new("/src/foo/a/b/c.o")));
+        assert!(excludes.matches(Path::new("/src/foo/c.o")));
+        assert!(!excludes.matches(Path::new("/srv/target")));
+        assert!(!excludes.matches(Path::new("/src/foo/c.rs")));
+    }
+
+    #[test]
+    fn test_literal_patterns() {
+        assert!(excludes(&["/"]).matches(Path::new("/etc")));
+
+        let excludes = excludes(&["node_modules", "/home/user/.cache"]);
+        assert!(excludes.matches(Path::new("/srv/app/node_modules/left-pad")));
+        assert!(excludes.matches(Path::new("/home/user/.cache/thumbnails")));
+        assert!(!excludes.matches(Path::new("/srv/.cache")));
+        assert!(!excludes.matches(Path::new("/home/user")));
+        assert!(!excludes.matches(Path::new("node_modules_old")));
+    }
+
+    #[test]
+    fn test_negation() {
+        let excludes = excludes(&["/home/user/*", "!/home/user/dev", "/home/user/dev/*.log"]);
+        assert!(excludes.matches(Path::new("/home/user/music/song.mp3")));
+        assert!(!excludes.matches(Path::new("/home/user/dev/bacify/README.md")));
+        assert!(excludes.matches(Path::new("/home/user/dev/build.log")));
+    }
+
+    #[test]
+    fn test_insensitive() {
+        let mut excludes = excludes(&["/data/*.JPG"]);
+        excludes.add_insensitive("/Photos/*.jpg");
+        excludes.add_insensitive("!/photos/keep.jpg");
+        assert!(excludes.matches(Path::new("/photos/IMG_1.JPG")));
+        assert!(!excludes.matches(Path::new("/PHOTOS/Keep.jpg")));
+        assert!(excludes.matches(Path::new("/data/a.JPG")));
+        assert!(!excludes.matches(Path::new("/data/a.jpg")));
+    }
+
+    #[test]
+    fn test_marker() -> io::Result<()> {
+        let temp_dir = tempfile::TempDir::with_prefix("bacify-test-")?;
+        let cache_dir = Marker::cache_dir();
+        let nobackup = Marker::new(".nobackup");
+        assert!(!cache_dir.present(temp_dir.path())?);
+        assert!(!nobackup.present(temp_dir.path())?);
+
+        std::fs::write(temp_dir.path().join("CACHEDIR.TAG"), "Signature: invalid")?;
+        assert!(!cache_dir.present(temp_dir.path())?);
+        std::fs::write(
+            temp_dir.path().join("CACHEDIR.TAG"),
+            "Signature: 8a477f597d28d172789f06886806bc55\n# Created by bacify\n",
+        )?;
+        assert!(cache_dir.present(temp_dir.path())?);
+
+        File::create(temp_dir.path().join(".nobackup"))?;
+        assert!(nobackup.present(temp_dir.path())?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ignore_file() {
+        let ignore = IgnoreFile::parse(
+            Path::new("/src"),
+            "# Build output\ntarget/\n*.log\n!keep.log\n/docs/*.html\nfoo/**/bar\n",
+        );
+        assert_eq!(ignore.matches(Path::new("/src/a/target"), true), Some(true));
+        assert_eq!(ignore.matches(Path::new("/src/a/target"), false), None);
+        assert_eq!(ignore.matches(Path::new("/src/a/b.log"), false), Some(true));
+        assert_eq!(
+            ignore.matches(Path::new("/src/a/keep.log"), false),
+            Some(false)
+        );
+        assert_eq!(
+            ignore.matches(Path::new("/src/docs/a.html"), false),
+            Some(true)
+        );
+        assert_eq!(ignore.matches(Path::new("/src/a/docs/a.html"), false), None);
+        assert_eq!(
+            ignore.matches(Path::new("/src/foo/a/b/bar"), false),
+            Some(true)
+        );
+        assert_eq!(ignore.matches(Path::new("/src/foo/bar"), false), Some(true));
+        assert_eq!(ignore.matches(Path::new("/other/b.log"), false), None);
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("2048"), Ok(2048));
+        assert_eq!(parse_size("500k"), Ok(500 * 1024));
+        assert_eq!(parse_size("1G"), Ok(1 << 30));
+        assert!(parse_size("1x").is_err());
+        assert!(parse_size("G").is_err());
+    }
+
+    #[test]
+    fn test_expand_env() {
+        std::env::set_var("BACIFY_TEST_DIR", "/home/user");
+        assert_eq!(expand_env("$BACIFY_TEST_DIR/tmp"), "/home/user/tmp");
+        assert_eq!(expand_env("${BACIFY_TEST_DIR}x/$"), "/home/userx/$");
+        assert_eq!(expand_env("/$BACIFY_TEST_UNSET/tmp"), "//tmp");
+    }
+}
@@ -1,27 +1,56 @@
-use chrono::prelude::*;
+use backend::{
+    Archive, Attributes, Backend, Borg, Contents, Filter, Inode, Kind, Kopia, Mirror, Repository,
+    Restic,
+};
+use chrono::{DateTime, FixedOffset};
 use clap::Parser;
 use env_logger::{Builder, Env, Target};
+use exclude::{Excludes, IgnoreFile, Marker};
 use log::{debug, error, info, warn};
-use serde_json::Value;
-use sha2::{Digest, Sha256};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs;
 use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::time::{Duration, SystemTime};
 use walkdir::WalkDir;
 
+mod backend;
+mod exclude;
+
 struct BackupVerifier {
     missing: HashSet<PathBuf>,
     corrupt: HashSet<PathBuf>,
-    backup_dir: PathBuf,
+    deleted: HashSet<PathBuf>,
+    changed: HashSet<PathBuf>,
+    new: HashSet<PathBuf>,
+    // Files with an older modification time than in the backup, with both times
+    time_anomalies: HashMap<PathBuf, (SystemTime, SystemTime)>,
+    // Files with different permissions, ownership or timestamps, with the differences
+    metadata_mismatches: HashMap<PathBuf, Vec<String>>,
+    // Files with different extended attributes or ACLs, with the differences
+    xattr_mismatches: HashMap<PathBuf, Vec<String>>,
+    // Hard linked files, by device and inode, with the inode of their counterparts
+    hardlinks: HashMap<Inode, Vec<(PathBuf, Option<Inode>)>>,
+    // Groups of hard links that aren't linked together in the backup
+    broken_hardlinks: Vec<Vec<PathBuf>>,
     backup_time: chrono::DateTime<chrono::FixedOffset>,
-    source_dir: PathBuf,
-    id: String,
-    excludes: Vec<String>,
+    source_dirs: Vec<PathBuf>,
+    excludes: Excludes,
+    markers: Vec<Marker>,
+    exclude_larger_than: Option<u64>,
+    one_file_system: bool,
+    ignore_files: bool,
     relative_path: bool,
     max_age: Option<humantime::Duration>,
+    // Report files deleted since the backup, if they were last modified before this long ago
+    report_deleted: Option<Duration>,
+    // List the changed and new files in the verdict, not just count them
+    list_changes: bool,
+    metadata_checks: Vec<MetadataCheck>,
+    check_xattrs: bool,
+    check_hardlinks: bool,
 }
 
 impl BackupVerifier {
@@ -29,64 +58,270 @@ impl BackupVerifier {
         BackupVerifier {
             missing: HashSet::new(),
             corrupt: HashSet::new(),
-            backup_dir: PathBuf::new(),
+            deleted: HashSet::new(),
+            changed: HashSet::new(),
+            new: HashSet::new(),
+            time_anomalies: HashMap::new(),
+            metadata_mismatches: HashMap::new(),
+            xattr_mismatches: HashMap::new(),
+            hardlinks: HashMap::new(),
+            broken_hardlinks: Vec::new(),
             backup_time: chrono::Local::now().fixed_offset(), // Placeholder, actual value would be set later
-            source_dir: PathBuf::new(),
-            id: String::new(), // Restic snapshot id
-            excludes: Vec::new(),
+            source_dirs: Vec::new(),
+            excludes: Excludes::default(),
+            markers: Vec::new(),
+            exclude_larger_than: None,
+            one_file_system: false,
+            ignore_files: false,
             relative_path,
             max_age,
+            report_deleted: None,
+            list_changes: false,
+            metadata_checks: Vec::new(),
+            check_xattrs: false,
+            check_hardlinks: false,
         }
     }
 
+    // Only checks the path itself, excluded directories are skipped during the walk
     fn excluded(&self, file: &Path) -> bool {
-        // TODO: Implement efficient check for exclusion
-        // A binary search could be implemented here if `self.excludes` is sorted
-        // TODO: Match restic's behavior, not just starts_with? but some globbing + extra magic
-        self.excludes
-            .iter()
-            .any(|exclude| file.starts_with(exclude))
+        self.excludes.matches(file)
+    }
+
+    // The part of the paths below `root` that isn't in the backup
+    fn prefix<'a>(&self, root: &'a Path) -> &'a Path {
+        if !self.relative_path {
+            // Paths in the snapshot are relative to its root, so the leading slash of
+            // an absolute file path has to go.
+            Path::new("/")
+        } else if self.source_dirs.len() > 1 {
+            // Several relative paths are stored side by side, each under its own name
+            root.parent().unwrap_or(root)
+        } else {
+            root
+        }
+    }
+
+    // Differences in the checked metadata, as far as the backup records it
+    fn compare_attributes(
+        &self,
+        local: &Attributes,
+        backup: &Attributes,
+        resolution: Duration,
+    ) -> Vec<String> {
+        let mut differences = Vec::new();
+        for check in &self.metadata_checks {
+            match check {
+                MetadataCheck::Mode => {
+                    if let (Some(local), Some(backup)) = (local.mode, backup.mode) {
+                        if local != backup {
+                            differences
+                                .push(format!("mode {:04o}, in backup {:04o}", local, backup));
+                        }
+                    }
+                }
+                MetadataCheck::Owner => {
+                    for (name, local, backup) in [
+                        ("uid", local.uid, backup.uid),
+                        ("gid", local.gid, backup.gid),
+                    ] {
+                        if let (Some(local), Some(backup)) = (local, backup) {
+                            if local != backup {
+                                differences
+                                    .push(format!("{} {}, in backup {}", name, local, backup));
+                            }
+                        }
+                    }
+                }
+                MetadataCheck::Names => {
+                    for (name, local, backup) in [
+                        ("user", &local.user, &backup.user),
+                        ("group", &local.group, &backup.group),
+                    ] {
+                        if let (Some(local), Some(backup)) = (local, backup) {
+                            if local != backup {
+                                differences
+                                    .push(format!("{} {}, in backup {}", name, local, backup));
+                            }
+                        }
+                    }
+                }
+                MetadataCheck::Atime | MetadataCheck::Ctime => {
+                    let (name, local, backup) = if *check == MetadataCheck::Atime {
+                        ("accessed", local.accessed, backup.accessed)
+                    } else {
+                        ("changed", local.changed, backup.changed)
+                    };
+                    if let (Some(local), Some(backup)) = (local, backup) {
+                        if backend::truncate(local, resolution)
+                            != backend::truncate(backup, resolution)
+                        {
+                            differences.push(format!(
+                                "{} {}, in backup {}",
+                                name,
+                                format_time(local),
+                                format_time(backup)
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        differences
+    }
+
+    // Compare the extended attributes of the file with the ones in the backup, if it records them
+    fn verify_xattrs(
+        &mut self,
+        backup: &dyn Contents,
+        relative_file: &Path,
+        file: &Path,
+    ) -> io::Result<()> {
+        let Some(backup_xattrs) = backup.xattrs(relative_file)? else {
+            debug!("No extended attributes in backup: {}", file.display());
+            return Ok(());
+        };
+        let xattrs = backend::read_xattrs(file)?;
+
+        let mut differences = Vec::new();
+        for (name, value) in &xattrs {
+            match backup_xattrs.get(name) {
+                None => differences.push(format!("{} missing in backup", name)),
+                Some(backup_value) if backup_value != value => {
+                    differences.push(format!("{} differs", name))
+                }
+                Some(_) => {}
+            }
+        }
+        for name in backup_xattrs.keys() {
+            if !xattrs.contains_key(name) {
+                differences.push(format!("{} only in backup", name));
+            }
+        }
+
+        if !differences.is_empty() {
+            warn!(
+                "Different extended attributes in backup: {} ({})",
+                file.display(),
+                differences.join(", ")
+            );
+            self.xattr_mismatches
+                .insert(file.to_path_buf(), differences);
+        }
+        Ok(())
+    }
+
+    fn add_hardlink(&mut self, file: &Path, metadata: &fs::Metadata, counterpart: Option<Inode>) {
+        // Linking changes the ctime, so the links might not have existed at backup time
+        let changed = SystemTime::UNIX_EPOCH + Duration::from_secs(metadata.ctime().max(0) as u64);
+        if changed > self.backup_time.into() {
+            debug!("Hard link changed since backup: {}", file.display());
+            return;
+        }
+        self.hardlinks
+            .entry((metadata.dev(), metadata.ino()))
+            .or_default()
+            .push((file.to_path_buf(), counterpart));
     }
 
-    fn sha256(&self, path: &Path) -> io::Result<[u8; 32]> {
-        let mut file = fs::File::open(path)?;
-        let mut hasher = Sha256::new();
-        io::copy(&mut file, &mut hasher)?;
-        let hash = hasher.finalize();
-        Ok(hash.into())
+    // Check that the hard links found in the source directories are hard links in the backup too
+    fn verify_hardlinks(&mut self) {
+        for links in self.hardlinks.values() {
+            // Links outside of the source directories or excluded ones can't be compared
+            if links.len() < 2 {
+                continue;
+            }
+            let inodes = links
+                .iter()
+                .map(|(_, inode)| *inode)
+                .collect::<Option<HashSet<_>>>();
+            match inodes {
+                None => debug!(
+                    "No inodes in backup for hard links of {}",
+                    links[0].0.display()
+                ),
+                Some(inodes) if inodes.len() > 1 => {
+                    let mut files: Vec<PathBuf> =
+                        links.iter().map(|(file, _)| file.clone()).collect();
+                    files.sort();
+                    warn!(
+                        "Hard links not linked together in backup: {}",
+                        files
+                            .iter()
+                            .map(|file| file.display().to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    );
+                    self.broken_hardlinks.push(files);
+                }
+                Some(_) => {}
+            }
+        }
     }
 
     // Verify the source file against the backup
-    fn verify_source_file(&mut self, file: &Path) -> io::Result<()> {
+    fn verify_source_file(
+        &mut self,
+        backup: &dyn Contents,
+        prefix: &Path,
+        file: &Path,
+    ) -> io::Result<()> {
         // Relative paths restore right into the temporary directory, but in the snapshot metadata
         // there is an absolute path.
         // Use --relative-path (or -r) to remove the leading path components.
-        let relative_file = if self.relative_path {
-            file.strip_prefix(self.source_dir.as_path())
-                .expect("Could not strip prefix")
-        } else {
-            // If file is an absolute Path we need to strip the leading slash, otherwise
-            // backup_dir.join(file) will return file, instead of the joined paths.
-            // See https://doc.rust-lang.org/std/path/struct.Path.html#method.join.
-            file.strip_prefix("/").unwrap_or(file)
-        };
-        let counterpart = self.backup_dir.join(relative_file);
+        let relative_file = file.strip_prefix(prefix).unwrap_or(file);
 
-        let file_metadata = fs::metadata(file)?;
+        // Symlinks are verified as links, whether their target exists or not
+        let file_metadata = fs::symlink_metadata(file)?;
         let file_birthtime = file_metadata.created()?;
+        let kind = Kind::from(file_metadata.file_type());
 
-        if counterpart.is_file() {
-            let counterpart_metadata = fs::metadata(&counterpart)?;
-            let counterpart_modified = counterpart_metadata.modified()?;
-            let file_modified = file_metadata.modified()?;
+        if let Some(mut counterpart) = backup.metadata(relative_file)?.filter(|c| c.kind == kind) {
+            if self.check_hardlinks && file_metadata.is_file() && file_metadata.nlink() > 1 {
+                self.add_hardlink(file, &file_metadata, counterpart.inode);
+            }
 
-            // Check if the modified times are the same
-            if file_modified == counterpart_modified {
-                // Compare file contents
-                let file_sha256 = self.sha256(file)?;
-                let counterpart_sha256 = self.sha256(&counterpart)?;
+            // Check if the modified times are the same, as far as the backup can tell
+            let resolution = backup.time_resolution();
+            let file_modified = backend::truncate(file_metadata.modified()?, resolution);
+            let counterpart_modified = backend::truncate(counterpart.modified, resolution);
+            let is_dir = kind == Kind::Dir;
+            if is_dir && file_modified != counterpart_modified {
+                // Adding or removing entries changes it, the entries are verified on their own
+                debug!("Directory modified since backup: {}", file.display());
+            }
+            if !is_dir && file_modified > counterpart_modified {
+                debug!("Changed since backup: {}", file.display());
+                self.changed.insert(file.to_path_buf());
+            } else if !is_dir && file_modified < counterpart_modified {
+                // The clock was set back, the file was replaced by an older copy or tampered with
+                warn!(
+                    "Modified timestamp older than in backup: {}",
+                    file.display()
+                );
+                self.time_anomalies.insert(
+                    file.to_path_buf(),
+                    (file_metadata.modified()?, counterpart.modified),
+                );
+            } else {
+                let same_content = match kind {
+                    // Compare file contents, no need to read them if the sizes differ already
+                    Kind::File => {
+                        file_metadata.len() == counterpart.size
+                            && backup.same_content(relative_file, file)?
+                    }
+                    // Not every backup records the target or device number
+                    Kind::Symlink => {
+                        let target = fs::read_link(file)?;
+                        counterpart.target.is_none_or(|t| t == target)
+                    }
+                    Kind::BlockDevice | Kind::CharDevice => counterpart
+                        .rdev
+                        .is_none_or(|rdev| rdev == file_metadata.rdev()),
+                    _ => true,
+                };
 
-                if file_sha256 == counterpart_sha256 {
+                if same_content {
                     debug!("Same content in backup: {}", file.display());
                 } else {
                     warn!(
@@ -95,12 +330,42 @@ impl BackupVerifier {
                     );
                     self.corrupt.insert(file.to_path_buf());
                 }
+
+                if !self.metadata_checks.is_empty() {
+                    let mut attributes = Attributes::from(&file_metadata);
+                    if self.metadata_checks.contains(&MetadataCheck::Names) {
+                        attributes = attributes.with_names();
+                        // Restored copies only have ids, their names are the local ones
+                        let backup_attributes = &counterpart.attributes;
+                        if backup_attributes.user.is_none() && backup_attributes.group.is_none() {
+                            counterpart.attributes = backup_attributes.clone().with_names();
+                        }
+                    }
+                    let differences =
+                        self.compare_attributes(&attributes, &counterpart.attributes, resolution);
+                    if !differences.is_empty() {
+                        warn!(
+                            "Different metadata in backup: {} ({})",
+                            file.display(),
+                            differences.join(", ")
+                        );
+                        self.metadata_mismatches
+                            .insert(file.to_path_buf(), differences);
+                    }
+                }
+
+                if self.check_xattrs {
+                    self.verify_xattrs(backup, relative_file, file)?;
+                }
             }
         } else if file_birthtime <= self.backup_time.into() {
             debug!("Missing in backup: {}", file.display());
             self.missing.insert(file.to_path_buf());
+        } else if kind == Kind::Dir {
+            debug!("New directory since backup: {}", file.display());
         } else {
-            debug!("Not in backup (too new): {}", file.display());
+            debug!("New since backup: {}", file.display());
+            self.new.insert(file.to_path_buf());
         }
 
         Ok(())
@@ -109,43 +374,87 @@ impl BackupVerifier {
     fn load_excludes(&self, excludes_file: PathBuf) -> Result<Vec<String>, Box<dyn Error>> {
         let file_contents = fs::read_to_string(excludes_file);
         match file_contents {
-            Ok(contents) => Ok(contents.lines().map(String::from).collect::<Vec<String>>()),
+            // Like restic, skip empty lines and comments and expand environment variables
+            Ok(contents) => Ok(contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(exclude::expand_env)
+                .collect::<Vec<String>>()),
             // If the file doesn't exist, return an empty list because no exclude file means no excludes
             Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
             Err(e) => Err(e.into()),
         }
     }
 
-    fn main(&mut self) -> Result<(), Box<dyn Error>> {
-        let snapshot_info = Command::new("restic")
-            .args(["snapshots", "--json", "--latest", "1"]) // Get metadata for the latest 1 snapshot
-            .output()?;
+    fn load_exclude_args(&mut self, args: &ExcludeArgs) -> Result<(), Box<dyn Error>> {
+        if args.exclude_caches {
+            self.markers.push(Marker::cache_dir());
+        }
+        self.markers
+            .extend(args.exclude_if_present.iter().map(|spec| Marker::new(spec)));
+        self.exclude_larger_than = args.exclude_larger_than;
+        self.one_file_system = args.one_file_system;
+        self.ignore_files = args.ignore_files;
 
-        if snapshot_info.stdout.is_empty() {
-            return Err(
-                "Couldn't find any snapshots. Did you set RESTIC_REPOSITORY and RESTIC_PASSWORD? Is restic installed?"
-                    .into(),
-            );
+        for file in args.exclude_file.iter().chain(&args.iexclude_file) {
+            if !file.is_file() {
+                return Err(format!("Couldn't find exclude file {:?}", file).into());
+            }
         }
 
-        let snapshot: Value = serde_json::from_slice(&snapshot_info.stdout)?;
-        let snapshot = snapshot.get(0).ok_or("No snapshot data available")?;
+        for pattern in &args.exclude {
+            self.excludes.add(pattern);
+        }
+        for file in &args.exclude_file {
+            for pattern in self.load_excludes(file.clone())? {
+                self.excludes.add(&pattern);
+            }
+        }
+        for pattern in &args.iexclude {
+            self.excludes.add_insensitive(pattern);
+        }
+        for file in &args.iexclude_file {
+            for pattern in self.load_excludes(file.clone())? {
+                self.excludes.add_insensitive(&pattern);
+            }
+        }
+        Ok(())
+    }
+
+    fn main(
+        &mut self,
+        backend: &dyn Backend,
+        filter: &Filter,
+        excludes: &ExcludeArgs,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut excludes = excludes.clone();
+        let mut snapshots = backend.snapshots()?;
+        if !filter.paths.is_empty() {
+            // Some backends only know the paths of a snapshot from its details, e.g. borg
+            snapshots = snapshots
+                .into_iter()
+                .map(|snapshot| backend.details(snapshot))
+                .collect::<Result<_, _>>()?;
+        }
+        let snapshot = filter.select(snapshots)?;
+        let snapshot = if filter.paths.is_empty() {
+            backend.details(snapshot)?
+        } else {
+            snapshot
+        };
+        self.relative_path |= backend.relative_paths();
 
-        self.backup_time = snapshot["time"]
-            .as_str()
-            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
-            .ok_or("Invalid snapshot time")?;
-        self.id = snapshot["id"]
-            .as_str()
-            .map(String::from)
-            .ok_or("Invalid snapshot id")?;
-        self.source_dir = snapshot["paths"][0]
-            .as_str()
-            .map(PathBuf::from)
-            .ok_or("Invalid source directory")?;
+        self.backup_time = snapshot.time;
+        if snapshot.paths.is_empty() {
+            return Err("Invalid source directory".into());
+        }
+        self.source_dirs = snapshot.paths.clone();
 
-        if !self.source_dir.is_dir() {
-            return Err(format!("Couldn't find source directory {:?}", self.source_dir).into());
+        for source_dir in &self.source_dirs {
+            if !source_dir.is_dir() {
+                return Err(format!("Couldn't find source directory {:?}", source_dir).into());
+            }
         }
 
         // Check if the backup is too old
@@ -157,50 +466,174 @@ impl BackupVerifier {
             }
         }
 
-        // Load excludes from ~/.backup_exclude
-        let home_dir = dirs::home_dir().ok_or("Could not find home directory")?;
-        let excludes_file = home_dir.join(".backup_exclude");
-        self.excludes = self.load_excludes(excludes_file)?;
+        if excludes.snapshot_excludes {
+            if snapshot.excludes.is_empty() {
+                info!("The snapshot doesn't record any exclude options");
+            } else {
+                info!(
+                    "Exclude options of the snapshot: {}",
+                    snapshot.excludes.join(" ")
+                );
+                excludes.extend(ExcludeArgs::parse_recorded(&snapshot.excludes)?);
+            }
+        }
+        if !excludes.has_patterns() {
+            // Load excludes from ~/.backup_exclude
+            let home_dir = dirs::home_dir().ok_or("Could not find home directory")?;
+            let excludes_file = home_dir.join(".backup_exclude");
+            if excludes_file.is_file() {
+                excludes.exclude_file.push(excludes_file);
+            }
+        }
+        self.load_exclude_args(&excludes)?;
 
         // Log some information about the snapshot
-        Command::new("restic")
-            .arg("stats")
-            .arg(&self.id)
-            .status()
-            .expect("Failed to execute restic stats");
+        backend.stats(&snapshot)?;
 
-        let temp_dir = tempfile::TempDir::with_prefix("bacify-")?;
-        self.backup_dir = temp_dir.path().to_owned();
-        self.restore()?;
-        self.verify()?;
+        let backup = backend.open(&snapshot)?;
+        if self.check_xattrs && !backup.records_xattrs() {
+            return Err(
+                "The backup doesn't record extended attributes, --check-xattrs can't be used"
+                    .into(),
+            );
+        }
+        self.verify(backup.as_ref())?;
+        self.verify_deleted(backup.as_ref())?;
 
         self.verdict()
     }
 
-    fn restore(&self) -> Result<(), Box<dyn Error>> {
-        Command::new("restic")
-            .args([
-                "restore",
-                &self.id,
-                "--target",
-                self.backup_dir.to_str().ok_or("Invalid backup directory")?,
-            ])
-            .status()?;
+    // The marker in the directory that excludes its contents, if there is one
+    fn marker(&self, dir: &Path) -> Option<&Marker> {
+        self.markers
+            .iter()
+            .find(|marker| match marker.present(dir) {
+                Ok(present) => present,
+                Err(e) => {
+                    warn!("Couldn't read {}: {}", dir.join(&marker.name).display(), e);
+                    false
+                }
+            })
+    }
+
+    // Like unreadable markers, an unreadable ignore file doesn't stop the verification
+    fn ignore_file(&self, dir: &Path) -> Option<IgnoreFile> {
+        IgnoreFile::load(dir).unwrap_or_else(|e| {
+            warn!(
+                "Couldn't read {}: {}",
+                dir.join(IgnoreFile::NAME).display(),
+                e
+            );
+            None
+        })
+    }
+
+    fn verify(&mut self, backup: &dyn Contents) -> io::Result<()> {
+        for source_dir in self.source_dirs.clone() {
+            info!("Verifying {}", source_dir.display());
+            let prefix = self.prefix(&source_dir);
+            // Mount points below the source directory are listed, but not their contents
+            let mut entries = WalkDir::new(&source_dir)
+                .same_file_system(self.one_file_system)
+                .into_iter();
+            // The ignore files of the directories above the current entry, with their depth
+            let mut ignores: Vec<(usize, IgnoreFile)> = Vec::new();
+            while let Some(entry) = entries.next() {
+                let Ok(entry) = entry else {
+                    continue;
+                };
+                let path = entry.path();
+                let is_dir = entry.file_type().is_dir();
+
+                ignores.retain(|(depth, _)| *depth < entry.depth());
+                let ignored = ignored_by(&ignores, path, is_dir);
+                // Like restic, don't look into excluded directories, even if a negated pattern
+                // matches a file in them
+                if ignored || self.excluded(path) {
+                    debug!("Excluded: {}", path.display());
+                    if is_dir {
+                        entries.skip_current_dir();
+                    }
+                    continue;
+                }
+
+                if is_dir {
+                    if self.ignore_files {
+                        if let Some(ignore) = self.ignore_file(path) {
+                            ignores.push((entry.depth(), ignore));
+                        }
+                    }
+
+                    // The source directory itself is the root of the backup with relative paths
+                    if path != prefix {
+                        self.verify_source_file(backup, prefix, path)?;
+                    }
+
+                    if let Some(marker) = self.marker(path) {
+                        debug!("Excluded by {}: {}", marker.name, path.display());
+                        // Only the directory and the marker itself are in the backup
+                        let marker = path.join(&marker.name);
+                        entries.skip_current_dir();
+                        if marker.is_file()
+                            && !ignored_by(&ignores, &marker, false)
+                            && !self.excluded(&marker)
+                        {
+                            self.verify_source_file(backup, prefix, &marker)?;
+                        }
+                    }
+                    continue;
+                }
+
+                // Backup tools don't store sockets, they only exist while a program listens
+                if entry.file_type().is_socket() {
+                    debug!("Skipped socket: {}", path.display());
+                    continue;
+                }
+
+                if let Some(limit) = self.exclude_larger_than {
+                    if entry.metadata()?.len() > limit {
+                        debug!("Excluded by size: {}", path.display());
+                        continue;
+                    }
+                }
+
+                self.verify_source_file(backup, prefix, path)?;
+            }
+        }
+        self.verify_hardlinks();
         Ok(())
     }
 
-    fn verify(&mut self) -> io::Result<()> {
-        for entry in WalkDir::new(&self.source_dir)
-            .into_iter()
-            .filter_map(|e| e.ok())
-            .filter(|e| e.path().is_file())
-        {
-            let file_path = entry.path();
-            if self.excluded(file_path) {
+    // Look for files in the backup that are gone from the source directories
+    fn verify_deleted(&mut self, backup: &dyn Contents) -> io::Result<()> {
+        let Some(min_age) = self.report_deleted else {
+            return Ok(());
+        };
+
+        let now = SystemTime::now();
+        for (path, entry) in backup.entries()? {
+            if !entry.is_file() && entry.kind != Kind::Symlink {
+                continue;
+            }
+            let Some(file) = self.source_dirs.iter().find_map(|source_dir| {
+                let file = self.prefix(source_dir).join(&path);
+                file.starts_with(source_dir).then_some(file)
+            }) else {
+                continue;
+            };
+            if file.symlink_metadata().is_ok() {
                 continue;
             }
 
-            self.verify_source_file(file_path)?;
+            if now.duration_since(entry.modified).unwrap_or_default() < min_age {
+                debug!(
+                    "Deleted since backup (recently modified): {}",
+                    file.display()
+                );
+            } else {
+                debug!("Deleted since backup: {}", file.display());
+                self.deleted.insert(file);
+            }
         }
         Ok(())
     }
@@ -208,32 +641,401 @@ impl BackupVerifier {
     fn verdict(&self) -> Result<(), Box<dyn Error>> {
         let mut result = Ok(());
 
-        if !self.missing.is_empty() {
-            warn!("Missing files that should be in the backup, the backup was created after the files were:");
-            for file in &self.missing {
-                warn!("{}", file.display());
+        for source_dir in &self.source_dirs {
+            // Changes since the backup are expected, they only show how outdated it is
+            let changed = self
+                .changed
+                .iter()
+                .filter(|file| file.starts_with(source_dir));
+            let new = self.new.iter().filter(|file| file.starts_with(source_dir));
+            info!(
+                "{} files changed and {} new in {} since the backup",
+                changed.clone().count(),
+                new.clone().count(),
+                source_dir.display()
+            );
+            if self.list_changes {
+                for file in changed {
+                    info!("Changed: {}", file.display());
+                }
+                for file in new {
+                    info!("New: {}", file.display());
+                }
+            }
+
+            let missing = self
+                .missing
+                .iter()
+                .filter(|file| file.starts_with(source_dir));
+            let corrupt = self
+                .corrupt
+                .iter()
+                .filter(|file| file.starts_with(source_dir));
+
+            if missing.clone().next().is_some() {
+                warn!(
+                    "Missing files in {} that should be in the backup, the backup was created after the files were:",
+                    source_dir.display()
+                );
+                for file in missing {
+                    warn!("{}", file.display());
+                }
+                result = Err("Verification failed".into());
+            }
+
+            if corrupt.clone().next().is_some() {
+                warn!(
+                    "Changed files found in {} that have the same modified time:",
+                    source_dir.display()
+                );
+                for file in corrupt {
+                    warn!("{}", file.display());
+                }
+                result = Err("Verification failed".into());
+            }
+
+            let time_anomalies = self
+                .time_anomalies
+                .iter()
+                .filter(|(file, _)| file.starts_with(source_dir));
+            if time_anomalies.clone().next().is_some() {
+                warn!(
+                    "Files found in {} that are older than in the backup:",
+                    source_dir.display()
+                );
+                for (file, (modified, backup_modified)) in time_anomalies {
+                    warn!(
+                        "{} (modified {}, in backup {})",
+                        file.display(),
+                        format_time(*modified),
+                        format_time(*backup_modified)
+                    );
+                }
+                result = Err("Verification failed".into());
+            }
+
+            let metadata_mismatches = self
+                .metadata_mismatches
+                .iter()
+                .filter(|(file, _)| file.starts_with(source_dir));
+            if metadata_mismatches.clone().next().is_some() {
+                warn!(
+                    "Files found in {} with different metadata than in the backup:",
+                    source_dir.display()
+                );
+                for (file, differences) in metadata_mismatches {
+                    warn!("{} ({})", file.display(), differences.join(", "));
+                }
+                result = Err("Verification failed".into());
+            }
+
+            let xattr_mismatches = self
+                .xattr_mismatches
+                .iter()
+                .filter(|(file, _)| file.starts_with(source_dir));
+            if xattr_mismatches.clone().next().is_some() {
+                warn!(
+                    "Files found in {} with different extended attributes or ACLs than in the backup:",
+                    source_dir.display()
+                );
+                for (file, differences) in xattr_mismatches {
+                    warn!("{} ({})", file.display(), differences.join(", "));
+                }
+                result = Err("Verification failed".into());
             }
-            result = Err("Verification failed".into());
-        }
 
-        if !self.corrupt.is_empty() {
-            warn!("Changed files found that have the same modified time:");
-            for file in &self.corrupt {
-                warn!("{}", file.display());
+            let broken_hardlinks = self
+                .broken_hardlinks
+                .iter()
+                .filter(|files| files[0].starts_with(source_dir));
+            if broken_hardlinks.clone().next().is_some() {
+                warn!(
+                    "Hard links found in {} that aren't linked together in the backup:",
+                    source_dir.display()
+                );
+                for files in broken_hardlinks {
+                    let files: Vec<String> = files
+                        .iter()
+                        .map(|file| file.display().to_string())
+                        .collect();
+                    warn!("{}", files.join(", "));
+                }
+                result = Err("Verification failed".into());
+            }
+
+            let deleted = self
+                .deleted
+                .iter()
+                .filter(|file| file.starts_with(source_dir));
+            if deleted.clone().next().is_some() {
+                warn!(
+                    "Files in the backup that were deleted from {}:",
+                    source_dir.display()
+                );
+                for file in deleted {
+                    warn!("{}", file.display());
+                }
+                result = Err("Verification failed".into());
             }
-            result = Err("Verification failed".into());
         }
         result
     }
 }
 
+// Whether the ignore files of the directories above `path` ignore it, deeper ones take precedence
+fn ignored_by(ignores: &[(usize, IgnoreFile)], path: &Path, is_dir: bool) -> bool {
+    ignores
+        .iter()
+        .rev()
+        .find_map(|(_, ignore)| ignore.matches(path, is_dir))
+        .unwrap_or(false)
+}
+
+fn format_time(time: SystemTime) -> String {
+    DateTime::<chrono::Local>::from(time).to_rfc3339()
+}
+
+/// Metadata that is compared for unchanged files, in addition to their contents
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum MetadataCheck {
+    /// Permission bits
+    Mode,
+    /// User and group id
+    Owner,
+    /// User and group name
+    Names,
+    /// Access time
+    Atime,
+    /// Inode change time
+    Ctime,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum BackendKind {
+    Restic,
+    /// Read a local restic repository without the restic binary
+    ResticNative,
+    Borg,
+    Kopia,
+}
+
+/// The exclude options of `restic backup`. Without any patterns `~/.backup_exclude` is used.
+#[derive(clap::Args, Clone, Debug, Default)]
+struct ExcludeArgs {
+    /// Use the exclude options recorded in the snapshot as well
+    #[arg(long)]
+    snapshot_excludes: bool,
+
+    /// Exclude a pattern, can be given multiple times
+    #[arg(short, long)]
+    exclude: Vec<String>,
+
+    /// Read exclude patterns from a file, can be given multiple times
+    #[arg(long)]
+    exclude_file: Vec<PathBuf>,
+
+    /// Same as --exclude, but ignores the case of paths
+    #[arg(long)]
+    iexclude: Vec<String>,
+
+    /// Same as --exclude-file, but ignores the case of paths
+    #[arg(long)]
+    iexclude_file: Vec<PathBuf>,
+
+    /// Exclude the contents of directories with a valid CACHEDIR.TAG
+    #[arg(long)]
+    exclude_caches: bool,
+
+    /// Exclude the contents of directories containing this file, given as name[:header]
+    #[arg(long)]
+    exclude_if_present: Vec<String>,
+
+    /// Exclude files larger than this size, e.g. 500M or 1G
+    #[arg(long, value_parser = exclude::parse_size)]
+    exclude_larger_than: Option<u64>,
+
+    /// Don't cross file system boundaries below the snapshot paths
+    #[arg(short = 'x', long)]
+    one_file_system: bool,
+
+    /// Skip files ignored by .bacifyignore files, which work like .gitignore
+    #[arg(long)]
+    ignore_files: bool,
+}
+
+impl ExcludeArgs {
+    fn has_patterns(&self) -> bool {
+        !(self.exclude.is_empty()
+            && self.exclude_file.is_empty()
+            && self.iexclude.is_empty()
+            && self.iexclude_file.is_empty())
+    }
+
+    fn parse_recorded(args: &[String]) -> Result<ExcludeArgs, Box<dyn Error>> {
+        #[derive(Parser)]
+        struct Recorded {
+            #[command(flatten)]
+            excludes: ExcludeArgs,
+        }
+        let recorded = Recorded::try_parse_from(
+            std::iter::once("bacify").chain(args.iter().map(String::as_str)),
+        )?;
+        Ok(recorded.excludes)
+    }
+
+    fn extend(&mut self, other: ExcludeArgs) {
+        self.exclude.extend(other.exclude);
+        self.exclude_file.extend(other.exclude_file);
+        self.iexclude.extend(other.iexclude);
+        self.iexclude_file.extend(other.iexclude_file);
+        self.exclude_caches |= other.exclude_caches;
+        self.exclude_if_present.extend(other.exclude_if_present);
+        self.exclude_larger_than = self.exclude_larger_than.or(other.exclude_larger_than);
+        self.one_file_system |= other.one_file_system;
+        self.ignore_files |= other.ignore_files;
+    }
+}
+
 #[derive(Parser, Debug)]
 struct Args {
+    #[arg(short, long, value_enum, default_value_t = BackendKind::Restic)]
+    backend: BackendKind,
+
     #[arg(short, long)]
     relative_path: bool,
 
     #[arg(short, long)]
     max_age: Option<humantime::Duration>,
+
+    /// Report files in the backup that were deleted from the source directories
+    #[arg(long)]
+    deleted: bool,
+
+    /// List the files changed or created since the backup, not just their number
+    #[arg(long)]
+    list_changes: bool,
+
+    /// Compare metadata of unchanged files as well, e.g. mode,owner
+    #[arg(long, value_enum, value_delimiter = ',')]
+    check_metadata: Vec<MetadataCheck>,
+
+    /// Compare extended attributes and POSIX ACLs of unchanged files as well
+    #[arg(long)]
+    check_xattrs: bool,
+
+    /// Check that hard linked files are hard links of each other in the backup too
+    #[arg(long)]
+    check_hardlinks: bool,
+
+    /// Like --deleted, but only report files last modified before this long ago, e.g. 7d
+    #[arg(long)]
+    deleted_older_than: Option<humantime::Duration>,
+
+    /// Don't restore the snapshot, read only the files that need to be compared (restic only)
+    #[arg(long, conflicts_with_all = ["mirror", "archive"])]
+    stream: bool,
+
+    /// Verify this snapshot instead of the latest one, a prefix of the id is enough
+    #[arg(long)]
+    snapshot: Option<String>,
+
+    /// Only consider snapshots of this host, can be given multiple times
+    #[arg(long)]
+    host: Vec<String>,
+
+    /// Only consider snapshots with these comma separated tags, can be given multiple times
+    #[arg(long)]
+    tag: Vec<String>,
+
+    /// Only consider snapshots including this path, can be given multiple times
+    #[arg(long)]
+    path: Vec<PathBuf>,
+
+    #[command(flatten)]
+    excludes: ExcludeArgs,
+
+    /// Compare restic chunk hashes instead of reading file contents (restic-native only)
+    #[arg(long)]
+    chunk_hashes: bool,
+
+    /// Verify against a plain copy of the source directory instead of a backup repository
+    #[arg(long, conflicts_with = "backend", requires = "source")]
+    mirror: Option<PathBuf>,
+
+    /// Verify against a tar (optionally gzip or zstd compressed) or zip archive
+    #[arg(long, conflicts_with_all = ["backend", "mirror"], requires = "source")]
+    archive: Option<PathBuf>,
+
+    /// Source directory, for backups that don't record it
+    #[arg(long)]
+    source: Option<PathBuf>,
+
+    /// Time of the backup in RFC 3339 format, e.g. 2024-04-01T12:00:00+02:00
+    #[arg(long, value_parser = DateTime::parse_from_rfc3339)]
+    backup_time: Option<DateTime<FixedOffset>>,
+
+    /// Use the modification time of this file as time of the backup
+    #[arg(long, conflicts_with = "backup_time")]
+    time_marker: Option<PathBuf>,
+}
+
+impl Args {
+    fn backup_time(&self) -> Result<Option<DateTime<FixedOffset>>, Box<dyn Error>> {
+        if let Some(time) = self.backup_time {
+            Ok(Some(time))
+        } else if let Some(marker) = &self.time_marker {
+            let modified = fs::metadata(marker)?.modified()?;
+            Ok(Some(
+                DateTime::<chrono::Local>::from(modified).fixed_offset(),
+            ))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn filter(&self) -> Filter {
+        Filter {
+            id: self.snapshot.clone(),
+            hosts: self.host.clone(),
+            tags: self.tag.clone(),
+            paths: self.path.clone(),
+        }
+    }
+
+    fn backend(&self) -> Result<Box<dyn Backend>, Box<dyn Error>> {
+        if let Some(mirror) = &self.mirror {
+            let source = self.source.clone().ok_or("Missing source directory")?;
+            let time = self
+                .backup_time()?
+                .ok_or("The time of the backup is unknown, use --backup-time or --time-marker")?;
+            return Ok(Box::new(Mirror::new(mirror.clone(), source, time)));
+        }
+
+        if let Some(archive) = &self.archive {
+            let source = self.source.clone().ok_or("Missing source directory")?;
+            return Ok(Box::new(Archive::new(
+                archive.clone(),
+                source,
+                self.backup_time()?,
+            )));
+        }
+
+        if self.stream && !matches!(self.backend, BackendKind::Restic) {
+            return Err("--stream is only supported by the restic backend".into());
+        }
+        if self.chunk_hashes && !matches!(self.backend, BackendKind::ResticNative) {
+            return Err("--chunk-hashes is only supported by the restic-native backend".into());
+        }
+
+        Ok(match self.backend {
+            BackendKind::Restic => Box::new(Restic::new(self.stream)),
+            BackendKind::ResticNative => {
+                Box::new(Repository::from_env()?.chunk_hashes(self.chunk_hashes))
+            }
+            BackendKind::Borg => Box::new(Borg),
+            BackendKind::Kopia => Box::new(Kopia),
+        })
+    }
 }
 
 fn main() {
@@ -250,7 +1052,18 @@ fn main() {
     }
 
     let mut verifier = BackupVerifier::new(args.relative_path, args.max_age);
-    match verifier.main() {
+    verifier.report_deleted = args
+        .deleted_older_than
+        .map(Into::into)
+        .or(args.deleted.then_some(Duration::ZERO));
+    verifier.list_changes = args.list_changes;
+    verifier.metadata_checks = args.check_metadata.clone();
+    verifier.check_xattrs = args.check_xattrs;
+    verifier.check_hardlinks = args.check_hardlinks;
+    match args
+        .backend()
+        .and_then(|backend| verifier.main(backend.as_ref(), &args.filter(), &args.excludes))
+    {
         Err(e) => {
             error!("Error: {}", e);
             std::process::exit(1)
@@ -294,31 +1107,505 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_load_exclude_args() -> Result<(), Box<dyn Error>> {
+        let temp_dir = tempfile::TempDir::with_prefix("bacify-test-")?;
+        let exclude_file_path = temp_dir.path().join("excludes");
+        fs::write(&exclude_file_path, "# Build output\n\n  target  \n")?;
+
+        let mut verifier = BackupVerifier::new(false, None);
+        verifier.load_exclude_args(&ExcludeArgs {
+            exclude: vec!["*.tmp".into()],
+            exclude_file: vec![exclude_file_path],
+            iexclude: vec!["*.jpg".into()],
+            ..Default::default()
+        })?;
+        assert!(verifier.excluded(Path::new("/home/user/dev/target/debug")));
+        assert!(verifier.excluded(Path::new("/home/user/file.tmp")));
+        assert!(verifier.excluded(Path::new("/home/user/IMG.JPG")));
+        assert!(!verifier.excluded(Path::new("/home/user/file.TMP")));
+
+        let missing = ExcludeArgs {
+            exclude_file: vec![temp_dir.path().join("missing")],
+            ..Default::default()
+        };
+        assert!(verifier.load_exclude_args(&missing).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_recorded_excludes() -> Result<(), Box<dyn Error>> {
+        let mut excludes = ExcludeArgs {
+            exclude: vec!["*.tmp".into()],
+            ..Default::default()
+        };
+        excludes.extend(ExcludeArgs::parse_recorded(&[
+            "--exclude".into(),
+            "/home/*/.cache".into(),
+            "--exclude-caches".into(),
+        ])?);
+        assert_eq!(excludes.exclude, ["*.tmp", "/home/*/.cache"]);
+        assert!(excludes.exclude_caches);
+        Ok(())
+    }
+
+    /// A source directory and its mirror, the backup was made after all test files were created.
+    struct Fixture {
+        source: tempfile::TempDir,
+        mirror: tempfile::TempDir,
+        verifier: BackupVerifier,
+    }
+
+    impl Fixture {
+        fn new() -> io::Result<Fixture> {
+            let source = tempfile::TempDir::with_prefix("bacify-test-")?;
+            let mirror = tempfile::TempDir::with_prefix("bacify-test-")?;
+            let mut verifier = BackupVerifier::new(true, None);
+            verifier.source_dirs = vec![source.path().to_owned()];
+            verifier.backup_time =
+                (chrono::Local::now() + chrono::Duration::hours(1)).fixed_offset();
+            Ok(Fixture {
+                source,
+                mirror,
+                verifier,
+            })
+        }
+
+        fn source(&self, path: &str) -> PathBuf {
+            self.source.path().join(path)
+        }
+
+        fn mirror(&self, path: &str) -> PathBuf {
+            self.mirror.path().join(path)
+        }
+
+        fn backup(&self) -> backend::Directory {
+            backend::Directory::new(self.mirror.path().to_owned())
+        }
+
+        fn verify(&mut self) -> io::Result<()> {
+            let backup = self.backup();
+            self.verifier.verify(&backup)
+        }
+    }
+
+    #[test]
+    fn test_verify_mirror() -> io::Result<()> {
+        let mut fixture = Fixture::new()?;
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1 << 30);
+        for (name, source, mirror) in [("same", "foo", "foo"), ("corrupt", "foo", "bar")] {
+            File::create(fixture.source(name))?.write_all(source.as_bytes())?;
+            let mut file = File::create(fixture.mirror(name))?;
+            file.write_all(mirror.as_bytes())?;
+            file.set_modified(modified)?;
+            File::options()
+                .write(true)
+                .open(fixture.source(name))?
+                .set_modified(modified)?;
+        }
+        File::create(fixture.source("missing"))?;
+        File::create(fixture.source("changed"))?;
+        File::create(fixture.mirror("changed"))?.set_modified(modified)?;
+        File::create(fixture.source("older"))?.set_modified(modified)?;
+        File::create(fixture.mirror("older"))?;
+
+        fixture.verify()?;
+
+        let verifier = &fixture.verifier;
+        assert_eq!(verifier.corrupt, HashSet::from([fixture.source("corrupt")]));
+        assert_eq!(verifier.missing, HashSet::from([fixture.source("missing")]));
+        assert_eq!(verifier.changed, HashSet::from([fixture.source("changed")]));
+        assert!(verifier.new.is_empty());
+        assert_eq!(
+            verifier.time_anomalies.keys().collect::<Vec<_>>(),
+            [&fixture.source("older")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_symlinks() -> io::Result<()> {
+        use std::os::unix::fs::symlink;
+
+        let mut fixture = Fixture::new()?;
+        let modified = filetime::FileTime::from_unix_time(1 << 30, 0);
+        for (name, source, mirror) in [
+            ("same", "file", "file"),
+            ("retargeted", "file", "other"),
+            ("dangling", "nonexistent", "nonexistent"),
+        ] {
+            symlink(source, fixture.source(name))?;
+            symlink(mirror, fixture.mirror(name))?;
+            for link in [fixture.source(name), fixture.mirror(name)] {
+                filetime::set_symlink_file_times(link, modified, modified)?;
+            }
+        }
+        symlink("nonexistent", fixture.source("missing"))?;
+        filetime::set_symlink_file_times(fixture.source("missing"), modified, modified)?;
+
+        fixture.verify()?;
+
+        let verifier = &fixture.verifier;
+        assert_eq!(
+            verifier.corrupt,
+            HashSet::from([fixture.source("retargeted")])
+        );
+        assert_eq!(verifier.missing, HashSet::from([fixture.source("missing")]));
+        assert!(verifier.changed.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_dirs_and_special_files() -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        use std::os::unix::net::UnixListener;
+
+        let mut fixture = Fixture::new()?;
+        let modified = filetime::FileTime::from_unix_time(1 << 30, 0);
+        for (dir, mode) in [
+            (fixture.source.path(), 0o755),
+            (fixture.mirror.path(), 0o700),
+        ] {
+            fs::create_dir(dir.join("dir"))?;
+            fs::set_permissions(dir.join("dir"), fs::Permissions::from_mode(mode))?;
+            filetime::set_symlink_file_times(dir.join("dir"), modified, modified)?;
+        }
+        fs::create_dir(fixture.source("empty"))?;
+        // Directory times aren't compared, in either direction
+        for (dir, seconds) in [
+            (fixture.source.path(), 1 << 29),
+            (fixture.mirror.path(), 1 << 30),
+        ] {
+            fs::create_dir(dir.join("older"))?;
+            let modified = filetime::FileTime::from_unix_time(seconds, 0);
+            filetime::set_symlink_file_times(dir.join("older"), modified, modified)?;
+        }
+        // Sockets aren't backed up
+        UnixListener::bind(fixture.source("socket"))?;
+
+        fixture.verifier.metadata_checks = vec![MetadataCheck::Mode];
+        fixture.verify()?;
+
+        let verifier = &fixture.verifier;
+        assert_eq!(verifier.missing, HashSet::from([fixture.source("empty")]));
+        assert!(verifier.corrupt.is_empty());
+        assert!(verifier.changed.is_empty());
+        assert!(verifier.time_anomalies.is_empty());
+        assert_eq!(
+            verifier.metadata_mismatches.keys().collect::<Vec<_>>(),
+            [&fixture.source("dir")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_hardlinks() -> io::Result<()> {
+        let mut fixture = Fixture::new()?;
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1 << 30);
+        for dir in [fixture.source.path(), fixture.mirror.path()] {
+            for name in ["linked", "copied"] {
+                File::create(dir.join(name))?.set_modified(modified)?;
+            }
+            fs::hard_link(dir.join("linked"), dir.join("linked2"))?;
+        }
+        fs::hard_link(fixture.source("copied"), fixture.source("copied2"))?;
+        fs::copy(fixture.mirror("copied"), fixture.mirror("copied2"))?;
+        File::options()
+            .write(true)
+            .open(fixture.mirror("copied2"))?
+            .set_modified(modified)?;
+
+        fixture.verifier.check_hardlinks = true;
+        fixture.verify()?;
+
+        assert_eq!(
+            fixture.verifier.broken_hardlinks,
+            [[fixture.source("copied"), fixture.source("copied2")]]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_metadata() -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut fixture = Fixture::new()?;
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1 << 30);
+        for (name, mode) in [("same", 0o640), ("different", 0o600)] {
+            let source = File::create(fixture.source(name))?;
+            source.set_modified(modified)?;
+            source.set_permissions(fs::Permissions::from_mode(0o640))?;
+            let mirror = File::create(fixture.mirror(name))?;
+            mirror.set_modified(modified)?;
+            mirror.set_permissions(fs::Permissions::from_mode(mode))?;
+        }
+
+        // The ctime of the mirror differs, but it can't be restored anyway
+        fixture.verifier.metadata_checks = vec![
+            MetadataCheck::Mode,
+            MetadataCheck::Owner,
+            MetadataCheck::Names,
+            MetadataCheck::Ctime,
+        ];
+        fixture.verify()?;
+
+        assert_eq!(
+            fixture.verifier.metadata_mismatches,
+            HashMap::from([(
+                fixture.source("different"),
+                vec!["mode 0640, in backup 0600".to_owned()]
+            )])
+        );
+        assert!(fixture.verifier.corrupt.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_xattrs() -> io::Result<()> {
+        let mut fixture = Fixture::new()?;
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1 << 30);
+        let source = fixture.source("file");
+        let mirror = fixture.mirror("file");
+        for file in [&source, &mirror] {
+            File::create(file)?.set_modified(modified)?;
+        }
+        if xattr::set(&source, "user.bacify", b"source").is_err() {
+            // Extended attributes aren't supported here
+            return Ok(());
+        }
+        xattr::set(&source, "user.same", b"value")?;
+        xattr::set(&source, "user.missing", b"value")?;
+        xattr::set(&mirror, "user.bacify", b"mirror")?;
+        xattr::set(&mirror, "user.same", b"value")?;
+        xattr::set(&mirror, "user.extra", b"value")?;
+
+        fixture.verifier.check_xattrs = true;
+        fixture.verify()?;
+
+        assert_eq!(
+            fixture.verifier.xattr_mismatches,
+            HashMap::from([(
+                source,
+                vec![
+                    "user.bacify differs".to_owned(),
+                    "user.missing missing in backup".to_owned(),
+                    "user.extra only in backup".to_owned()
+                ]
+            )])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_new() -> io::Result<()> {
+        let mut fixture = Fixture::new()?;
+        File::create(fixture.source("new"))?;
+
+        fixture.verifier.backup_time =
+            (chrono::Local::now() - chrono::Duration::hours(1)).fixed_offset();
+        fixture.verify()?;
+
+        assert_eq!(fixture.verifier.new, HashSet::from([fixture.source("new")]));
+        assert!(fixture.verifier.missing.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_multiple_paths() -> io::Result<()> {
+        let mut fixture = Fixture::new()?;
+        // Only the first path is in the backup
+        for dir in ["etc", "home"] {
+            fs::create_dir(fixture.source(dir))?;
+            File::create(fixture.source(dir).join("file"))?;
+        }
+        fs::create_dir(fixture.mirror("etc"))?;
+        fs::copy(fixture.source("etc/file"), fixture.mirror("etc/file"))?;
+
+        fixture.verifier.source_dirs = vec![fixture.source("etc"), fixture.source("home")];
+        fixture.verify()?;
+
+        assert_eq!(
+            fixture.verifier.missing,
+            HashSet::from([fixture.source("home"), fixture.source("home/file")])
+        );
+        assert!(fixture.verifier.verdict().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_markers() -> Result<(), Box<dyn Error>> {
+        let mut fixture = Fixture::new()?;
+        for dir in ["cache", "skip"] {
+            fs::create_dir(fixture.source(dir))?;
+            fs::create_dir(fixture.mirror(dir))?;
+            File::create(fixture.source(dir).join("file"))?;
+        }
+        fs::write(
+            fixture.source("cache/CACHEDIR.TAG"),
+            "Signature: 8a477f597d28d172789f06886806bc55",
+        )?;
+        File::create(fixture.source("skip/.nobackup"))?;
+        // Not in the backup at all, and the marker is ignored
+        fs::create_dir(fixture.source("gone"))?;
+        File::create(fixture.source("gone/.nobackup"))?;
+        fs::write(fixture.source(".bacifyignore"), "/gone/.nobackup\n")?;
+        fs::copy(
+            fixture.source(".bacifyignore"),
+            fixture.mirror(".bacifyignore"),
+        )?;
+
+        fixture.verifier.load_exclude_args(&ExcludeArgs {
+            exclude_caches: true,
+            exclude_if_present: vec![".nobackup".into()],
+            ignore_files: true,
+            ..Default::default()
+        })?;
+        fixture.verify()?;
+
+        // The directories and markers are backed up, but nothing else in the directories
+        assert_eq!(
+            fixture.verifier.missing,
+            HashSet::from([
+                fixture.source("cache/CACHEDIR.TAG"),
+                fixture.source("skip/.nobackup"),
+                fixture.source("gone")
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_ignore_files() -> Result<(), Box<dyn Error>> {
+        let mut fixture = Fixture::new()?;
+        let project = fixture.source("project");
+        fs::create_dir_all(project.join("target"))?;
+        fs::write(fixture.source(".bacifyignore"), "*.log\n")?;
+        fs::write(project.join(".bacifyignore"), "target/\n!keep.log\n")?;
+        for file in [
+            "a.log",
+            "project/keep.log",
+            "project/b.log",
+            "project/target/c",
+        ] {
+            File::create(fixture.source(file))?;
+        }
+
+        fixture.verifier.load_exclude_args(&ExcludeArgs {
+            ignore_files: true,
+            ..Default::default()
+        })?;
+        fixture.verify()?;
+
+        assert_eq!(
+            fixture.verifier.missing,
+            HashSet::from([
+                fixture.source(".bacifyignore"),
+                project.clone(),
+                project.join(".bacifyignore"),
+                project.join("keep.log")
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_unreadable_ignore_file() -> Result<(), Box<dyn Error>> {
+        let mut fixture = Fixture::new()?;
+        // Reading a directory fails, even as root
+        fs::create_dir(fixture.source(".bacifyignore"))?;
+        File::create(fixture.source("file"))?;
+
+        fixture.verifier.load_exclude_args(&ExcludeArgs {
+            ignore_files: true,
+            ..Default::default()
+        })?;
+        fixture.verify()?;
+
+        assert!(fixture.verifier.missing.contains(&fixture.source("file")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_deleted() -> Result<(), Box<dyn Error>> {
+        let mut fixture = Fixture::new()?;
+        let old = SystemTime::now() - Duration::from_secs(30 * 24 * 60 * 60);
+        fs::create_dir(fixture.mirror("dir"))?;
+        File::create(fixture.mirror("dir/old"))?.set_modified(old)?;
+        File::create(fixture.mirror("new"))?;
+
+        fixture.verifier.report_deleted = Some(Duration::from_secs(7 * 24 * 60 * 60));
+        let backup = fixture.backup();
+        fixture.verifier.verify_deleted(&backup)?;
+
+        assert_eq!(
+            fixture.verifier.deleted,
+            HashSet::from([fixture.source("dir/old")])
+        );
+        assert!(fixture.verifier.verdict().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_larger_than() -> Result<(), Box<dyn Error>> {
+        let mut fixture = Fixture::new()?;
+        fs::write(fixture.source("small"), [0; 10])?;
+        fs::write(fixture.source("large"), [0; 2000])?;
+
+        fixture.verifier.load_exclude_args(&ExcludeArgs {
+            exclude_larger_than: Some(exclude::parse_size("1k")?),
+            ..Default::default()
+        })?;
+        fixture.verify()?;
+
+        assert_eq!(
+            fixture.verifier.missing,
+            HashSet::from([fixture.source("small")])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_prunes_excluded_dirs() -> Result<(), Box<dyn Error>> {
+        let mut fixture = Fixture::new()?;
+        fs::create_dir(fixture.source("node_modules"))?;
+        File::create(fixture.source("node_modules/keep"))?;
+
+        // The negated pattern can't re-include a file in an excluded directory
+        fixture.verifier.load_exclude_args(&ExcludeArgs {
+            exclude: vec!["node_modules".into(), "!keep".into()],
+            ..Default::default()
+        })?;
+        fixture.verify()?;
+
+        assert!(fixture.verifier.missing.is_empty());
+        Ok(())
+    }
+
     #[test]
     fn test_excluded_exact_match() {
         let mut verifier = BackupVerifier::new(false, None);
-        verifier.excludes.push("/home/user/exclude_this".into());
+        verifier.excludes.add("/home/user/exclude_this");
         assert!(verifier.excluded(Path::new("/home/user/exclude_this")));
     }
 
     #[test]
     fn test_excluded_starts_with_match() {
         let mut verifier = BackupVerifier::new(false, None);
-        verifier.excludes.push("/home/user/exclude".into());
+        verifier.excludes.add("/home/user/exclude");
         assert!(verifier.excluded(Path::new("/home/user/exclude/subdir")));
     }
 
     #[test]
     fn test_not_excluded_no_match() {
         let mut verifier = BackupVerifier::new(false, None);
-        verifier.excludes.push("/home/user/exclude".into());
+        verifier.excludes.add("/home/user/exclude");
         assert!(!verifier.excluded(Path::new("/home/user/include")));
     }
 
     #[test]
     fn test_not_excluded_partial_match() {
         let mut verifier = BackupVerifier::new(false, None);
-        verifier.excludes.push("/home/user/exclude".into());
+        verifier.excludes.add("/home/user/exclude");
         assert!(!verifier.excluded(Path::new("/home/user/exclude_this")));
     }
 }